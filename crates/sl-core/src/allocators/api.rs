@@ -0,0 +1,306 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/api
+ *
+ * Purpose:
+ *    `Allocator` (allocator-api2) for the counting and tracing wrappers,
+ *    so a single collection can be given its own accounted allocator
+ *    instead of measuring the whole process:
+ *
+ *      let counting = Counting::<System, AtomicCounter>::new(System);
+ *      let mut v = allocator_api2::vec::Vec::new_in(&counting);
+ *      ...
+ *      let (total, active) = counting.counts();
+ *
+ *    With `alloc-api-nightly` the trait is core's `Allocator`, so the std
+ *    collections (`Vec::new_in`, ...) take these wrappers as well. That
+ *    switches allocator-api2 to its nightly mode for the whole build, so
+ *    other crates built on it (e.g. hashbrown) need theirs enabled too.
+ *
+ *    Allocations made this way still go through the allocator hooks
+ *    (regions, watermarks, no-alloc guards, ...).
+ *
+ */
+
+use core::{
+    alloc::{
+        GlobalAlloc,
+        Layout,
+    },
+    ptr::NonNull,
+};
+
+use allocator_api2::alloc::{
+    AllocError,
+    Allocator,
+};
+
+
+// Generates the `Allocator` methods on top of the wrapper's `GlobalAlloc`.
+macro_rules! global_alloc_methods {
+    () => {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> { alloc_block(self, layout, false) }
+
+        fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            alloc_block(self, layout, true)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) { free_block(self, ptr, layout) }
+
+        unsafe fn grow(
+            &self,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            resize_block(self, ptr, old_layout, new_layout, false)
+        }
+
+        unsafe fn grow_zeroed(
+            &self,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            resize_block(self, ptr, old_layout, new_layout, true)
+        }
+
+        unsafe fn shrink(
+            &self,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            resize_block(self, ptr, old_layout, new_layout, false)
+        }
+    };
+}
+
+
+cfg_alloc_count! {
+    use super::{
+        Counter,
+        Counting,
+    };
+
+    unsafe impl<A, C> Allocator for Counting<A, C>
+    where
+        A: GlobalAlloc,
+        C: Counter,
+    {
+        global_alloc_methods!();
+    }
+}
+
+cfg_alloc_trace! {
+    use super::{
+        Tracing,
+        Tracker,
+    };
+
+    unsafe impl<A, T> Allocator for Tracing<A, T>
+    where
+        A: GlobalAlloc,
+        T: Tracker,
+    {
+        global_alloc_methods!();
+    }
+}
+
+
+//
+// `Allocator` semantics over `GlobalAlloc`
+//
+
+// `GlobalAlloc` can't do zero sized blocks; those get a dangling (but
+// aligned) pointer and never reach the allocator.
+fn dangling(layout: Layout) -> NonNull<[u8]> {
+    let ptr = NonNull::new(core::ptr::without_provenance_mut(layout.align())).expect("alignment is never zero");
+    NonNull::slice_from_raw_parts(ptr, 0)
+}
+
+fn alloc_block<G: GlobalAlloc>(alloc: &G, layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+    if layout.size() == 0 {
+        return Ok(dangling(layout));
+    }
+
+    // SAFETY: the layout has a non-zero size.
+    let ptr = unsafe {
+        if zeroed {
+            alloc.alloc_zeroed(layout)
+        } else {
+            alloc.alloc(layout)
+        }
+    };
+
+    NonNull::new(ptr)
+        .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+        .ok_or(AllocError)
+}
+
+unsafe fn free_block<G: GlobalAlloc>(alloc: &G, ptr: NonNull<u8>, layout: Layout) {
+    if layout.size() != 0 {
+        alloc.dealloc(ptr.as_ptr(), layout);
+    }
+}
+
+// Grow or shrink; the caller guarantees the direction matches the call.
+unsafe fn resize_block<G: GlobalAlloc>(
+    alloc: &G,
+    ptr: NonNull<u8>,
+    old_layout: Layout,
+    new_layout: Layout,
+    zeroed: bool,
+) -> Result<NonNull<[u8]>, AllocError> {
+    let (old_size, new_size) = (old_layout.size(), new_layout.size());
+
+    if old_size == 0 {
+        return alloc_block(alloc, new_layout, zeroed);
+    }
+
+    if new_size == 0 {
+        alloc.dealloc(ptr.as_ptr(), old_layout);
+        return Ok(dangling(new_layout));
+    }
+
+    // `realloc` keeps the alignment, so only usable when it doesn't change.
+    if old_layout.align() == new_layout.align() {
+        let new_ptr = NonNull::new(alloc.realloc(ptr.as_ptr(), old_layout, new_size)).ok_or(AllocError)?;
+        if zeroed && new_size > old_size {
+            new_ptr.as_ptr().add(old_size).write_bytes(0, new_size - old_size);
+        }
+
+        return Ok(NonNull::slice_from_raw_parts(new_ptr, new_size));
+    }
+
+    let new_ptr = alloc_block(alloc, new_layout, zeroed)?;
+    core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, old_size.min(new_size));
+    alloc.dealloc(ptr.as_ptr(), old_layout);
+
+    Ok(new_ptr)
+}
+
+
+#[cfg(all(test, feature = "std", feature = "alloc-count"))]
+mod tests {
+    use std::alloc::System;
+
+    use super::*;
+    use crate::allocators::AtomicCounter;
+
+
+    fn counting() -> Counting<System, AtomicCounter> { Counting::new(System) }
+
+    fn fill(block: NonNull<[u8]>, len: usize) {
+        // SAFETY: callers pass blocks at least `len` bytes long.
+        unsafe { core::slice::from_raw_parts_mut(block.as_ptr() as *mut u8, len) }
+            .iter_mut()
+            .enumerate()
+            .for_each(|(idx, b)| *b = idx as u8 + 1);
+    }
+
+    fn bytes<'a>(block: NonNull<[u8]>) -> &'a [u8] {
+        // SAFETY: only read while the block is alive.
+        unsafe { block.as_ref() }
+    }
+
+    #[test]
+    fn collections_are_counted() {
+        let counting = counting();
+
+        let mut v = allocator_api2::vec::Vec::new_in(&counting);
+        assert_eq!(counting.counts(), (0, 0));
+
+        v.push(1u64);
+        assert_eq!(counting.counts(), (1, 1));
+
+        v.extend(0..64);
+        let (total, active) = counting.counts();
+        assert!(total > 1);
+        assert_eq!(active, 1);
+
+        drop(v);
+        assert_eq!(counting.counts().1, 0);
+    }
+
+    #[test]
+    fn resize_changing_alignment() {
+        let counting = counting();
+        let small = Layout::from_size_align(16, 8).unwrap();
+        let big = Layout::from_size_align(64, 64).unwrap();
+        let tiny = Layout::from_size_align(8, 16).unwrap();
+
+        let block = counting.allocate(small).unwrap();
+        fill(block, 16);
+
+        // SAFETY: `block` came from `counting` with `small`, and so on down.
+        unsafe {
+            let grown = counting.grow(block.cast(), small, big).unwrap();
+            assert_eq!(grown.len(), 64);
+            assert!((grown.as_ptr() as *mut u8).addr().is_multiple_of(64));
+            assert_eq!(&bytes(grown)[..16], &(1..=16).collect::<Vec<u8>>()[..]);
+            assert_eq!(counting.counts(), (2, 1));
+
+            let shrunk = counting.shrink(grown.cast(), big, tiny).unwrap();
+            assert_eq!(shrunk.len(), 8);
+            assert!((shrunk.as_ptr() as *mut u8).addr().is_multiple_of(16));
+            assert_eq!(bytes(shrunk), &(1..=8).collect::<Vec<u8>>()[..]);
+            assert_eq!(counting.counts(), (3, 1));
+
+            counting.deallocate(shrunk.cast(), tiny);
+        }
+        assert_eq!(counting.counts(), (3, 0));
+    }
+
+    #[test]
+    fn zero_sized_blocks_skip_the_allocator() {
+        let counting = counting();
+        let empty = Layout::from_size_align(0, 32).unwrap();
+        let some = Layout::from_size_align(24, 32).unwrap();
+
+        let block = counting.allocate(empty).unwrap();
+        assert_eq!(block.len(), 0);
+        assert!((block.as_ptr() as *mut u8).addr().is_multiple_of(32));
+        assert_eq!(counting.counts(), (0, 0));
+
+        // SAFETY: every block is handed back with the layout it was made with.
+        unsafe {
+            let grown = counting.grow(block.cast(), empty, some).unwrap();
+            assert_eq!(grown.len(), 24);
+            assert_eq!(counting.counts(), (1, 1));
+
+            let shrunk = counting.shrink(grown.cast(), some, empty).unwrap();
+            assert_eq!(shrunk.len(), 0);
+            assert_eq!(counting.counts(), (1, 0));
+
+            counting.deallocate(shrunk.cast(), empty);
+        }
+        assert_eq!(counting.counts(), (1, 0));
+    }
+
+    #[test]
+    fn grow_zeroed_clears_the_new_tail() {
+        let counting = counting();
+        let small = Layout::from_size_align(16, 8).unwrap();
+
+        // Once with `realloc` (same alignment), once with a copy.
+        for big in [Layout::from_size_align(256, 8).unwrap(), Layout::from_size_align(256, 128).unwrap()] {
+            let block = counting.allocate(small).unwrap();
+            fill(block, 16);
+
+            // SAFETY: `block` came from `counting` with `small`.
+            unsafe {
+                let grown = counting.grow_zeroed(block.cast(), small, big).unwrap();
+                let grown_bytes = bytes(grown);
+                assert_eq!(&grown_bytes[..16], &(1..=16).collect::<Vec<u8>>()[..]);
+                assert!(grown_bytes[16..].iter().all(|b| *b == 0));
+
+                counting.deallocate(grown.cast(), big);
+            }
+        }
+        assert_eq!(counting.counts().1, 0);
+    }
+}
@@ -0,0 +1,170 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/autodump
+ *
+ * Purpose:
+ *    Opt-in memory reports on panic and at process exit, so crashes and
+ *    normal shutdowns leave a report behind without a `trace_block!`:
+ *
+ *      install_dump_hooks(&GLOBAL, DumpConfig::new("/var/tmp"));
+ *
+ *    A panic writes `<prefix>.<pid>.panic-<n>.log` (naming the panicking
+ *    thread) and then runs the previously installed panic hook; returning
+ *    from main or calling `std::process::exit` writes
+ *    `<prefix>.<pid>.exit.log`. The report is whatever the allocator
+ *    provides (`MemoryReport`) followed by the region stats.
+ *
+ */
+
+use std::{
+    io::{
+        self,
+        BufWriter,
+        Write,
+    },
+    path::PathBuf,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+        Once,
+    },
+};
+
+use super::{
+    live_bytes,
+    region,
+};
+
+
+static INSTALLED: Mutex<Option<Installed>> = Mutex::new(None);
+static PANIC_HOOK: Once = Once::new();
+static AT_EXIT: Once = Once::new();
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Anything that can describe the process's memory use (the global
+/// allocators).
+pub trait MemoryReport: Sync {
+    fn write_report(&self, out: &mut dyn Write) -> io::Result<()>;
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DumpConfig {
+    // Directory the reports are written to
+    pub dir:      PathBuf,
+    // File name prefix; the pid and the reason are appended
+    pub prefix:   String,
+    pub on_panic: bool,
+    pub at_exit:  bool,
+}
+
+impl DumpConfig {
+    /// Reports on panic and at exit, written to `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir:      dir.into(),
+            prefix:   "sl-mem".to_string(),
+            on_panic: true,
+            at_exit:  true,
+        }
+    }
+}
+
+struct Installed {
+    report: &'static dyn MemoryReport,
+    config: DumpConfig,
+}
+
+
+/// Installs the panic hook and / or the at-exit handler asked for by
+/// `config`. Calling it again replaces the report and configuration; hooks
+/// are only ever installed once.
+pub fn install_dump_hooks(report: &'static dyn MemoryReport, config: DumpConfig) {
+    let (on_panic, at_exit) = (config.on_panic, config.at_exit);
+    *INSTALLED.lock().expect("unable to lock dump hooks") = Some(Installed { report, config });
+
+    if on_panic {
+        PANIC_HOOK.call_once(|| {
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                let thread = std::thread::current();
+                let reason = format!(
+                    "panic on thread '{}': {}",
+                    thread.name().unwrap_or("<unnamed>"),
+                    info
+                );
+                let n = PANICS.fetch_add(1, Ordering::Relaxed);
+                dump(&format!("panic-{}", n), &reason, |c| c.on_panic);
+
+                previous(info);
+            }));
+        });
+    }
+
+    if at_exit {
+        AT_EXIT.call_once(|| {
+            // SAFETY: registers a plain `extern "C" fn()`.
+            if unsafe { atexit(dump_at_exit) } != 0 {
+                log::warn!("unable to register the at-exit memory report");
+            }
+        });
+    }
+}
+
+/// Writes a report right now (same location / format as the hooks).
+pub fn dump_now(tag: &str) -> io::Result<PathBuf> {
+    let installed = INSTALLED.lock().expect("unable to lock dump hooks");
+    let Some(installed) = installed.as_ref() else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no dump hooks installed"));
+    };
+
+    write_dump(installed, tag, "requested")
+}
+
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> std::ffi::c_int;
+}
+
+extern "C" fn dump_at_exit() { dump("exit", "process exit", |c| c.at_exit); }
+
+fn dump(tag: &str, reason: &str, enabled: impl Fn(&DumpConfig) -> bool) {
+    // Never panic or block from here (this thread may already be inside
+    // `dump_now`); a report that can't be written is dropped.
+    let Ok(installed) = INSTALLED.try_lock() else {
+        return;
+    };
+
+    if let Some(installed) = installed.as_ref().filter(|i| enabled(&i.config)) {
+        match write_dump(installed, tag, reason) {
+            | Ok(path) => eprintln!("memory report written to {}", path.display()),
+            | Err(e) => eprintln!("failed to write memory report: {}", e),
+        }
+    }
+}
+
+fn write_dump(installed: &Installed, tag: &str, reason: &str) -> io::Result<PathBuf> {
+    let config = &installed.config;
+    let path = config
+        .dir
+        .join(format!("{}.{}.{}.log", config.prefix, std::process::id(), tag));
+
+    let mut out = BufWriter::new(std::fs::File::create(&path)?);
+    writeln!(out, "=============== MEMORY REPORT ===============")?;
+    writeln!(out, "reason: {}", reason)?;
+    writeln!(out, "pid: {}", std::process::id())?;
+    writeln!(out, "live bytes: {}\n", live_bytes())?;
+
+    installed.report.write_report(&mut out)?;
+    region::dump_regions(&mut out)?;
+    out.flush()?;
+
+    Ok(path)
+}
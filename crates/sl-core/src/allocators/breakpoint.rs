@@ -0,0 +1,151 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/breakpoint
+ *
+ * Purpose:
+ *    Break on the Nth allocation (a la `_CrtSetBreakAlloc`). `Tracing`
+ *    numbers every allocation it sees, and those numbers are the `[ID: n]`
+ *    values in its reports; for a deterministic program, re-running with
+ *    a break on a leaked block's id stops at the exact allocation.
+ *
+ *    Set it from code with `break_on_alloc`, or from the environment:
+ *
+ *      SL_ALLOC_BREAK=<id>[:trap|:panic]
+ *
+ *    `Trap` raises a breakpoint trap (SIGTRAP; stops under a debugger,
+ *    kills the process otherwise). `Panic` prints the allocation and its
+ *    stack to stderr, then aborts (unwinding out of an allocator isn't
+ *    allowed, so it can't really panic).
+ *    Hooks run on the allocating thread; anything they allocate is not
+ *    tracked (and doesn't consume ids).
+ *
+ */
+
+use std::{
+    alloc::Layout,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        Once,
+    },
+};
+
+
+const ENV_VAR: &str = "SL_ALLOC_BREAK";
+const NONE: usize = usize::MAX;
+
+static BREAK_AT: AtomicUsize = AtomicUsize::new(NONE);
+static ACTION: Mutex<Option<AllocBreak>> = Mutex::new(None);
+static FROM_ENV: Once = Once::new();
+
+
+#[derive(Clone)]
+pub enum AllocBreak {
+    // Breakpoint trap; stops right in the allocator under a debugger
+    Trap,
+    // Print the allocation's id, size and stack, then abort
+    Panic,
+    // Calls the hook with the allocation's id and layout (must not panic)
+    Hook(Arc<dyn Fn(usize, Layout) + Send + Sync>),
+}
+
+impl AllocBreak {
+    pub fn hook<F>(hook: F) -> Self
+    where
+        F: Fn(usize, Layout) + Send + Sync + 'static,
+    {
+        Self::Hook(Arc::new(hook))
+    }
+}
+
+
+/// Runs `action` when allocation number `id` happens. Replaces any
+/// earlier break (including one from `SL_ALLOC_BREAK`).
+pub fn break_on_alloc(id: usize, action: AllocBreak) {
+    FROM_ENV.call_once(|| {});
+    *ACTION.lock().expect("unable to lock alloc break") = Some(action);
+    BREAK_AT.store(id, Ordering::Release);
+}
+
+pub fn clear_alloc_break() {
+    FROM_ENV.call_once(|| {});
+    BREAK_AT.store(NONE, Ordering::Release);
+    *ACTION.lock().expect("unable to lock alloc break") = None;
+}
+
+
+fn from_env() {
+    let Ok(value) = std::env::var(ENV_VAR) else {
+        return;
+    };
+
+    let (id, action) = value.split_once(':').unwrap_or((&value, "trap"));
+    let action = match action {
+        | "trap" => AllocBreak::Trap,
+        | "panic" => AllocBreak::Panic,
+        | _ => {
+            log::warn!("ignoring {}='{}': unknown action '{}'", ENV_VAR, value, action);
+            return;
+        },
+    };
+
+    match id.trim().parse() {
+        | Ok(id) => {
+            *ACTION.lock().expect("unable to lock alloc break") = Some(action);
+            BREAK_AT.store(id, Ordering::Release);
+        },
+        | Err(_) => log::warn!("ignoring {}='{}': bad allocation id", ENV_VAR, value),
+    }
+}
+
+fn trap() {
+    // SAFETY: a breakpoint instruction; execution resumes after it when a
+    // debugger continues.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe {
+        std::arch::asm!("int3");
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!("brk #0xf000");
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    std::process::abort();
+}
+
+
+//
+// Called from the allocator (outside its lock) for every numbered
+// allocation
+//
+#[inline]
+pub(crate) fn check(id: usize, layout: Layout) {
+    FROM_ENV.call_once(from_env);
+
+    if BREAK_AT.load(Ordering::Acquire) != id {
+        return;
+    }
+
+    let action = ACTION.lock().expect("unable to lock alloc break").clone();
+    match action {
+        | Some(AllocBreak::Trap) => trap(),
+        | Some(AllocBreak::Panic) => {
+            eprintln!(
+                "allocation {} ({} bytes) reached\n{}",
+                id,
+                layout.size(),
+                std::backtrace::Backtrace::force_capture()
+            );
+            std::process::abort();
+        },
+        | Some(AllocBreak::Hook(hook)) => hook(id, layout),
+        | None => {},
+    }
+}
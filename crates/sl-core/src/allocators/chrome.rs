@@ -0,0 +1,345 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/chrome
+ *
+ * Purpose:
+ *    Allocation activity in the Chrome Trace Event format, for Perfetto
+ *    (ui.perfetto.dev) or chrome://tracing:
+ *
+ *      chrome::start(ChromeConfig::default());
+ *      ...
+ *      chrome::stop().unwrap().write_json(&mut file)?;
+ *
+ *    Every thread gets its own track, named after the thread, holding its
+ *    allocations / frees (instant events with the size) and the allocation
+ *    regions it enters (`alloc_region!`, as begin / end slices). A process
+ *    wide counter track follows live bytes. `trace_block!` / `trace_fn!`
+ *    scopes get a slice as well (named by the tag / function), and
+ *    `chrome::scope` adds one for any other block.
+ *
+ *    Events are buffered in memory until the trace is stopped; past
+ *    `max_events` they are dropped (and counted). A realloc shows up as an
+ *    alloc of the new size followed by a free of the old one.
+ *
+ */
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+        PoisonError,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use super::live_bytes;
+
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+static NEXT_TID: AtomicUsize = AtomicUsize::new(1);
+
+thread_entry_guard!(CHROME_GUARD);
+
+thread_local! {
+    // (track id, generation of the last recording that named the track)
+    static TRACK: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChromeConfig {
+    // Instant event per allocation / free (otherwise only the counter)
+    pub allocs:     bool,
+    // Smaller allocations only update the live bytes counter
+    pub min_size:   usize,
+    pub max_events: usize,
+}
+
+impl Default for ChromeConfig {
+    fn default() -> Self {
+        Self {
+            allocs:     true,
+            min_size:   0,
+            max_events: 1 << 22,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Alloc(usize),
+    Free(usize),
+    // Counter update only
+    Live,
+    Enter(&'static str),
+    Exit(&'static str),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Event {
+    time:  Duration,
+    track: usize,
+    live:  usize,
+    kind:  Kind,
+}
+
+struct Recording {
+    generation: usize,
+    config:     ChromeConfig,
+    start:      Instant,
+    events:     Vec<Event>,
+    tracks:     Vec<(usize, String)>,
+    dropped:    usize,
+}
+
+impl Recording {
+    fn push(&mut self, kind: Kind) {
+        if self.events.len() >= self.config.max_events {
+            self.dropped += 1;
+            return;
+        }
+
+        let kind = match kind {
+            | Kind::Alloc(size) | Kind::Free(size) if !self.config.allocs || size < self.config.min_size => {
+                Kind::Live
+            },
+            | kind => kind,
+        };
+
+        let event = Event {
+            time: self.start.elapsed(),
+            track: self.track(),
+            live: live_bytes(),
+            kind,
+        };
+        self.events.push(event);
+    }
+
+    // Track of the current thread, named the first time the thread shows
+    // up in this recording.
+    fn track(&mut self) -> usize {
+        let generation = self.generation;
+        let mut name = None;
+
+        let track = TRACK
+            .try_with(|t| {
+                let (mut track, seen) = t.get();
+                if track == 0 {
+                    track = NEXT_TID.fetch_add(1, Ordering::Relaxed);
+                }
+                if seen != generation {
+                    name = Some(std::thread::current().name().map(str::to_string));
+                }
+                t.set((track, generation));
+                track
+            })
+            .unwrap_or(0);
+
+        if let Some(name) = name {
+            let name = name.unwrap_or_else(|| format!("thread {}", track));
+            self.tracks.push((track, name));
+        }
+
+        track
+    }
+}
+
+
+/// A stopped recording, ready to be written out.
+pub struct ChromeTrace {
+    pid:     u32,
+    events:  Vec<Event>,
+    tracks:  Vec<(usize, String)>,
+    dropped: usize,
+}
+
+
+/// Slice on the current thread's track until the guard is dropped. Unlike
+/// a region nothing is accounted to it.
+pub fn scope(name: &'static str) -> ScopeGuard {
+    enter_region(name);
+    ScopeGuard { name }
+}
+
+
+#[must_use = "the slice ends when the guard is dropped"]
+pub struct ScopeGuard {
+    name: &'static str,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) { exit_region(self.name); }
+}
+
+
+/// Starts recording, discarding any recording in progress.
+pub fn start(config: ChromeConfig) {
+    let recording = Recording {
+        generation: GENERATION.fetch_add(1, Ordering::Relaxed) + 1,
+        config,
+        start: Instant::now(),
+        events: Vec::new(),
+        tracks: Vec::new(),
+        dropped: 0,
+    };
+
+    let old = {
+        let mut guard = RECORDING.lock().expect("unable to lock chrome trace");
+        ACTIVE.store(true, Ordering::Release);
+        guard.replace(recording)
+    };
+
+    // Freed after unlocking; the frees come back through `record`.
+    drop(old);
+}
+
+/// Stops recording and returns the trace (None if none was recording).
+pub fn stop() -> Option<ChromeTrace> {
+    let recording = {
+        let mut guard = RECORDING.lock().expect("unable to lock chrome trace");
+        ACTIVE.store(false, Ordering::Release);
+        guard.take()?
+    };
+
+    Some(ChromeTrace {
+        pid:     std::process::id(),
+        events:  recording.events,
+        tracks:  recording.tracks,
+        dropped: recording.dropped,
+    })
+}
+
+
+impl ChromeTrace {
+    pub fn len(&self) -> usize { self.events.len() }
+
+    pub fn is_empty(&self) -> bool { self.events.is_empty() }
+
+    /// Events lost to `max_events`.
+    pub fn dropped(&self) -> usize { self.dropped }
+
+    /// Writes the trace as a JSON object (`traceEvents` array plus
+    /// metadata).
+    pub fn write_json<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        let pid = self.pid;
+        let process = std::env::args().next().unwrap_or_else(|| "process".to_string());
+
+        writeln!(out, "{{\"displayTimeUnit\":\"ms\",")?;
+        writeln!(out, "\"otherData\":{{\"dropped_events\":{}}},", self.dropped)?;
+        writeln!(out, "\"traceEvents\":[")?;
+        write!(
+            out,
+            "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+            pid,
+            escape(&process)
+        )?;
+
+        for (track, name) in self.tracks.iter() {
+            write!(
+                out,
+                ",\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+                pid,
+                track,
+                escape(name)
+            )?;
+        }
+
+        for e in self.events.iter() {
+            let ts = e.time.as_nanos() as f64 / 1000.0;
+
+            match e.kind {
+                | Kind::Alloc(size) | Kind::Free(size) => write!(
+                    out,
+                    ",\n{{\"name\":\"{}\",\"cat\":\"memory\",\"ph\":\"i\",\"s\":\"t\",\"ts\":{:.3},\"pid\":{},\
+                     \"tid\":{},\"args\":{{\"size\":{}}}}}",
+                    if matches!(e.kind, Kind::Alloc(_)) { "alloc" } else { "free" },
+                    ts,
+                    pid,
+                    e.track,
+                    size
+                )?,
+                | Kind::Enter(name) | Kind::Exit(name) => write!(
+                    out,
+                    ",\n{{\"name\":\"{}\",\"cat\":\"region\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":{},\"tid\":{}}}",
+                    escape(name),
+                    if matches!(e.kind, Kind::Enter(_)) { "B" } else { "E" },
+                    ts,
+                    pid,
+                    e.track
+                )?,
+                | Kind::Live => {},
+            }
+
+            if !matches!(e.kind, Kind::Enter(_) | Kind::Exit(_)) {
+                write!(
+                    out,
+                    ",\n{{\"name\":\"live bytes\",\"ph\":\"C\",\"ts\":{:.3},\"pid\":{},\"args\":{{\"bytes\":{}}}}}",
+                    ts, pid, e.live
+                )?;
+            }
+        }
+
+        writeln!(out, "\n]}}")
+    }
+}
+
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            | '"' => escaped.push_str("\\\""),
+            | '\\' => escaped.push_str("\\\\"),
+            | c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            | c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn record(kind: Kind) {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return;
+    }
+
+    // Anything allocated while recording (the event buffer, thread names)
+    // comes back through here and is skipped.
+    no_reentry_per_thread!(CHROME_GUARD, {
+        // Called from inside the allocator, so never panic; a recording
+        // left poisoned by a panicking thread is still usable.
+        if let Some(recording) = RECORDING.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+            recording.push(kind);
+        }
+    });
+}
+
+
+//
+// Hooks called from the allocators and regions
+//
+#[inline]
+pub(crate) fn record_alloc(size: usize) { record(Kind::Alloc(size)); }
+
+#[inline]
+pub(crate) fn record_dealloc(size: usize) { record(Kind::Free(size)); }
+
+#[inline]
+pub(crate) fn enter_region(name: &'static str) { record(Kind::Enter(name)); }
+
+#[inline]
+pub(crate) fn exit_region(name: &'static str) { record(Kind::Exit(name)); }
@@ -0,0 +1,149 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/counter
+ *
+ * Purpose:
+ *    Counter implementations used by the counting allocator.
+ *
+ *    `AtomicCounter` is a single shared atomic. Cheap to read, but every
+ *    thread hammers the same cache line on every allocation. It's the
+ *    default for `Counting`.
+ *
+ *    `ShardedCounter` spreads updates over cache-line padded shards, one
+ *    per thread (threads are assigned round-robin), and sums them on read.
+ *    Updates from different threads no longer contend. Individual shards
+ *    may go negative (a thread freeing memory another thread allocated),
+ *    so they are summed as signed values. Needs `std` for the thread-local
+ *    shard assignment. Opt in with `Counting<A, ShardedCounter>`.
+ *
+ *    A sharded read is not an atomic snapshot: the shards are loaded one
+ *    after the other, so updates racing with the read may be partially
+ *    counted. Once the counter is quiet the sum is exact; while it's busy
+ *    a count that should be near zero could come out negative, and reads
+ *    clamp that to zero.
+ *
+ */
+
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+
+cfg_std! {
+    const SHARDS: usize = 32;
+
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        static SHARD: std::cell::Cell<usize> = const { std::cell::Cell::new(usize::MAX) };
+    }
+}
+
+
+pub trait Counter {
+    // Associated const (rather than a `new()`) so allocators holding
+    // counters can still be built in a `static`.
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: Self;
+
+    fn add(&self, v: usize);
+    fn sub(&self, v: usize);
+    fn get(&self) -> usize;
+}
+
+
+//
+// Single shared atomic
+//
+pub struct AtomicCounter(AtomicUsize);
+
+impl Counter for AtomicCounter {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: Self = Self(AtomicUsize::new(0));
+
+    #[inline]
+    fn add(&self, v: usize) { self.0.fetch_add(v, Ordering::Relaxed); }
+
+    #[inline]
+    fn sub(&self, v: usize) { self.0.fetch_sub(v, Ordering::Relaxed); }
+
+    #[inline]
+    fn get(&self) -> usize { self.0.load(Ordering::Relaxed) }
+}
+
+
+cfg_std! {
+    //
+    // Per-thread shards, summed on read
+    //
+    #[repr(align(128))]
+    struct Shard(AtomicUsize);
+
+    pub struct ShardedCounter {
+        shards: [Shard; SHARDS],
+    }
+
+    impl Counter for ShardedCounter {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: Self = Self {
+            shards: [const { Shard(AtomicUsize::new(0)) }; SHARDS],
+        };
+
+        #[inline]
+        fn add(&self, v: usize) { self.shards[shard()].0.fetch_add(v, Ordering::Relaxed); }
+
+        #[inline]
+        fn sub(&self, v: usize) { self.shards[shard()].0.fetch_sub(v, Ordering::Relaxed); }
+
+        fn get(&self) -> usize {
+            let sum = self
+                .shards
+                .iter()
+                .fold(0isize, |sum, s| sum.wrapping_add(s.0.load(Ordering::Relaxed) as isize));
+            sum.max(0) as usize
+        }
+    }
+
+
+    #[inline]
+    fn shard() -> usize {
+        SHARD
+            .try_with(|s| {
+                let mut idx = s.get();
+                if idx == usize::MAX {
+                    idx = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+                    s.set(idx);
+                }
+                idx
+            })
+            .unwrap_or(0)
+    }
+}
+
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn sharded_counter_sums_across_threads() {
+        let counter = ShardedCounter::ZERO;
+
+        counter.add(100);
+        std::thread::scope(|s| {
+            s.spawn(|| counter.sub(60));
+        });
+        assert_eq!(counter.get(), 40);
+
+        // More freed than allocated (as a torn read can see) reads as zero.
+        std::thread::scope(|s| {
+            s.spawn(|| counter.sub(50));
+        });
+        assert_eq!(counter.get(), 0);
+    }
+}
@@ -11,70 +11,115 @@
  *
  */
 
-use std::{
-    alloc::{
-        GlobalAlloc,
-        Layout,
-    },
-    sync::atomic::{
-        AtomicUsize,
-        Ordering,
-    },
+use core::alloc::{
+    GlobalAlloc,
+    Layout,
 };
 
-pub struct Counting<A = std::alloc::System>
+#[cfg(feature = "std")]
+use super::{
+    autodump::MemoryReport,
+    hooks,
+};
+use super::{
+    AtomicCounter,
+    Counter,
+};
+
+// The counter defaults to `AtomicCounter` with or without `std`; a
+// `ShardedCounter` has to be asked for.
+#[cfg(feature = "std")]
+pub struct Counting<A = std::alloc::System, C = AtomicCounter>
+where
+    A: GlobalAlloc,
+    C: Counter,
+{
+    inner:  A,
+    active: C,
+    total:  C,
+}
+
+// No system allocator to fall back on; the inner allocator is required.
+#[cfg(not(feature = "std"))]
+pub struct Counting<A, C = AtomicCounter>
 where
     A: GlobalAlloc,
+    C: Counter,
 {
     inner:  A,
-    active: AtomicUsize,
-    total:  AtomicUsize,
+    active: C,
+    total:  C,
 }
 
-impl Counting<std::alloc::System> {
+#[cfg(feature = "std")]
+impl Counting<std::alloc::System, AtomicCounter> {
     pub const fn default() -> Self {
         Self {
             inner:  std::alloc::System,
-            active: AtomicUsize::new(0),
-            total:  AtomicUsize::new(0),
+            active: AtomicCounter::ZERO,
+            total:  AtomicCounter::ZERO,
         }
     }
 }
 
-impl<A> Counting<A>
+impl<A, C> Counting<A, C>
 where
     A: GlobalAlloc,
+    C: Counter,
 {
     pub const fn new(inner: A) -> Self {
         Self {
             inner,
-            active: AtomicUsize::new(0),
-            total: AtomicUsize::new(0),
+            active: C::ZERO,
+            total: C::ZERO,
         }
     }
 
-    pub fn counts(&self) -> (usize, usize) {
-        (
-            self.total.load(Ordering::Relaxed),
-            self.active.load(Ordering::Relaxed),
-        )
+    pub fn counts(&self) -> (usize, usize) { (self.total.get(), self.active.get()) }
+}
+
+#[cfg(feature = "std")]
+impl<A, C> MemoryReport for Counting<A, C>
+where
+    A: GlobalAlloc + Sync,
+    C: Counter + Sync,
+{
+    fn write_report(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let (total, active) = self.counts();
+        writeln!(out, "=============== ALLOCATION COUNTS ===============")?;
+        writeln!(out, "Allocations (Total {}, Active {})", total, active)
     }
 }
 
-unsafe impl<A> GlobalAlloc for Counting<A>
+unsafe impl<A, C> GlobalAlloc for Counting<A, C>
 where
     A: GlobalAlloc,
+    C: Counter,
 {
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.total.fetch_add(1, Ordering::Relaxed);
-        self.active.fetch_add(1, Ordering::Relaxed);
+        self.total.add(1);
+        self.active.add(1);
+        #[cfg(feature = "std")]
+        hooks::on_alloc(layout);
         self.inner.alloc(layout)
     }
 
     #[inline]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.active.sub(1);
+        #[cfg(feature = "std")]
+        hooks::on_dealloc(layout);
         self.inner.dealloc(ptr, layout)
     }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Counted the same as the default alloc + dealloc, but lets the
+        // inner allocator grow the block in place.
+        self.total.add(1);
+        #[cfg(feature = "std")]
+        hooks::on_realloc(layout, Layout::from_size_align_unchecked(new_size, layout.align()));
+        self.inner.realloc(ptr, layout, new_size)
+    }
 }
@@ -0,0 +1,1126 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/dump
+ *
+ * Purpose:
+ *    Dump formats shared between the in-process trackers and the offline
+ *    tools. Covers the "raw" trace format (unresolved instruction addresses
+ *    plus the loaded module map) and the human-readable allocation report,
+ *    so a report symbolized later looks identical to one resolved
+ *    in-process.
+ *
+ *    Raw format (one record per line):
+ *      # sl-core raw trace v1
+ *      module <start> <end> <offset> <build-id | -> <path>
+ *      hide <symbol prefix>
+ *      alloc <id> <ptr> <size> <align> <region> <ip> <ip> ...
+ *      zalloc <id> <ptr> <size> <align> <region> <ip> <ip> ...
+ *      realloc <id> <old ptr> <new ptr> <old size> <new size> <align> <region> <ip> ...
+ *      free <ptr>
+ *
+ *    Whitespace in a region name becomes `_` and an empty name is written as `-`.
+ *
+ */
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    io::{
+        BufRead,
+        Error,
+        ErrorKind,
+    },
+    path::Path,
+};
+
+use super::filter::FilterPolicy;
+
+
+const RAW_HEADER: &str = "# sl-core raw trace v1";
+const SNAPSHOT_HEADER: &str = "# sl-core snapshot v1";
+
+
+//
+// Executable mapping of a loaded object (from /proc/self/maps)
+//
+#[derive(Clone, Debug)]
+pub struct Module {
+    pub start:    u64,
+    pub end:      u64,
+    pub offset:   u64,
+    pub build_id: Option<String>,
+    pub path:     String,
+}
+
+impl Module {
+    pub fn contains(&self, ip: u64) -> bool { ip >= self.start && ip < self.end }
+
+    /// Offset into the object file for an address inside this mapping.
+    pub fn file_offset(&self, ip: u64) -> u64 { ip - self.start + self.offset }
+}
+
+
+//
+// Single event from a raw trace
+//
+#[derive(Clone, Debug)]
+pub enum Event {
+    Alloc {
+        id:     usize,
+        ptr:    usize,
+        size:   usize,
+        align:  usize,
+        zeroed: bool,
+        region: String,
+        ips:    Vec<u64>,
+    },
+    Realloc {
+        id:       usize,
+        old_ptr:  usize,
+        new_ptr:  usize,
+        old_size: usize,
+        new_size: usize,
+        align:    usize,
+        region:   String,
+        ips:      Vec<u64>,
+    },
+    Free {
+        ptr: usize,
+    },
+}
+
+impl Event {
+    pub fn write_raw<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        let (region, ips) = match self {
+            | Event::Alloc {
+                id,
+                ptr,
+                size,
+                align,
+                zeroed,
+                region,
+                ips,
+            } => {
+                let kind = if *zeroed { "zalloc" } else { "alloc" };
+                write!(out, "{} {} {:#x} {} {}", kind, id, ptr, size, align)?;
+                (region, ips)
+            },
+            | Event::Realloc {
+                id,
+                old_ptr,
+                new_ptr,
+                old_size,
+                new_size,
+                align,
+                region,
+                ips,
+            } => {
+                write!(
+                    out,
+                    "realloc {} {:#x} {:#x} {} {} {}",
+                    id, old_ptr, new_ptr, old_size, new_size, align
+                )?;
+                (region, ips)
+            },
+            | Event::Free { ptr } => return writeln!(out, "free {:#x}", ptr),
+        };
+
+        match region.as_str() {
+            | "" => write!(out, " -")?,
+            | r => write!(out, " {}", r.replace(char::is_whitespace, "_"))?,
+        }
+        for ip in ips {
+            write!(out, " {:#x}", ip)?;
+        }
+        writeln!(out)
+    }
+}
+
+
+//
+// Fully parsed raw trace
+//
+#[derive(Clone, Debug, Default)]
+pub struct RawDump {
+    pub modules: Vec<Module>,
+    pub events:  Vec<Event>,
+    // Symbol prefixes the writer asks reports to hide (`FilterPolicy::hide`)
+    pub hide:    Vec<String>,
+}
+
+impl RawDump {
+    pub fn read<R: BufRead>(input: R) -> std::io::Result<Self> {
+        let mut dump = RawDump::default();
+        let mut lines = input.lines();
+
+        match lines.next() {
+            | Some(Ok(l)) if l.trim_end() == RAW_HEADER => {},
+            | _ => return Err(invalid("missing raw trace header")),
+        }
+
+        for line in lines {
+            let line = line?;
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                | Some("module") => {
+                    let start = parse_hex(parts.next())?;
+                    let end = parse_hex(parts.next())?;
+                    let offset = parse_hex(parts.next())?;
+                    let build_id = match parts.next() {
+                        | Some("-") => None,
+                        | Some(id) => Some(id.to_string()),
+                        | None => return Err(invalid("module record is truncated")),
+                    };
+                    let path = parts.collect::<Vec<_>>().join(" ");
+
+                    dump.modules.push(Module {
+                        start,
+                        end,
+                        offset,
+                        build_id,
+                        path,
+                    });
+                },
+                | Some("hide") => {
+                    let prefix = parts.next().ok_or_else(|| invalid("hide record is truncated"))?;
+                    dump.hide.push(prefix.to_string());
+                },
+                | Some(kind @ ("alloc" | "zalloc")) => {
+                    let id = parse_dec(parts.next())?;
+                    let ptr = parse_hex(parts.next())? as usize;
+                    let size = parse_dec(parts.next())?;
+                    let align = parse_dec(parts.next())?;
+                    let (region, ips) = parse_site(parts)?;
+
+                    dump.events.push(Event::Alloc {
+                        id,
+                        ptr,
+                        size,
+                        align,
+                        zeroed: kind == "zalloc",
+                        region,
+                        ips,
+                    });
+                },
+                | Some("realloc") => {
+                    let id = parse_dec(parts.next())?;
+                    let old_ptr = parse_hex(parts.next())? as usize;
+                    let new_ptr = parse_hex(parts.next())? as usize;
+                    let old_size = parse_dec(parts.next())?;
+                    let new_size = parse_dec(parts.next())?;
+                    let align = parse_dec(parts.next())?;
+                    let (region, ips) = parse_site(parts)?;
+
+                    dump.events.push(Event::Realloc {
+                        id,
+                        old_ptr,
+                        new_ptr,
+                        old_size,
+                        new_size,
+                        align,
+                        region,
+                        ips,
+                    });
+                },
+                | Some("free") => {
+                    let ptr = parse_hex(parts.next())? as usize;
+                    dump.events.push(Event::Free { ptr });
+                },
+                | Some(s) if s.starts_with('#') => {},
+                | None => {},
+                | Some(s) => return Err(invalid(&format!("unknown record type '{}'", s))),
+            }
+        }
+
+        Ok(dump)
+    }
+}
+
+pub fn write_raw_header<Writer: std::io::Write + ?Sized>(out: &mut Writer) -> std::io::Result<()> {
+    writeln!(out, "{}", RAW_HEADER)
+}
+
+/// Asks reports built from the dump to hide frames under `prefix`, e.g.
+/// the writer's own.
+pub fn write_raw_hide<Writer: std::io::Write + ?Sized>(out: &mut Writer, prefix: &str) -> std::io::Result<()> {
+    writeln!(out, "hide {}", prefix)
+}
+
+pub fn write_raw_module<Writer: std::io::Write + ?Sized>(
+    out: &mut Writer,
+    module: &Module,
+) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "module {:#x} {:#x} {:#x} {} {}",
+        module.start,
+        module.end,
+        module.offset,
+        module.build_id.as_deref().unwrap_or("-"),
+        module.path,
+    )
+}
+
+
+//
+// Resolved symbol for a single (possibly inlined) frame
+//
+#[derive(Clone, Debug, Default)]
+pub struct Symbol {
+    pub name:     Option<String>,
+    pub filename: Option<String>,
+    pub lineno:   Option<u32>,
+}
+
+
+//
+// Human-readable allocation report. Feed it every event in order and then
+// `finish` it to get the leak / unknown free / growth chain summaries.
+//
+pub struct Report {
+    filter:        FilterPolicy,
+    leaked:        HashMap<usize, Block>,
+    // Live blocks the filter dropped; freeing them is not an unknown free
+    filtered:      HashSet<usize>,
+    unknown_frees: HashSet<usize>,
+    chains:        Vec<Vec<Step>>,
+}
+
+// Live block along with every size it has had
+struct Block {
+    region: String,
+    chain:  Vec<Step>,
+}
+
+#[derive(Clone, Copy)]
+struct Step {
+    id:    usize,
+    size:  usize,
+    moved: bool,
+}
+
+impl Block {
+    fn id(&self) -> usize { self.chain[0].id }
+
+    fn size(&self) -> usize { self.chain[self.chain.len() - 1].size }
+}
+
+impl Report {
+    pub fn new(filter: &FilterPolicy) -> Self {
+        Self {
+            filter: filter.clone(),
+            leaked: HashMap::new(),
+            filtered: HashSet::new(),
+            unknown_frees: HashSet::new(),
+            chains: Vec::new(),
+        }
+    }
+
+    pub fn record<Writer: std::io::Write + ?Sized>(
+        &mut self,
+        out: &mut Writer,
+        event: &Event,
+        symbols: &[Symbol],
+    ) -> std::io::Result<()> {
+        let size = match event {
+            | Event::Alloc { size, .. } => *size,
+            | Event::Realloc { new_size, .. } => *new_size,
+            | Event::Free { ptr } => {
+                match self.leaked.remove(ptr) {
+                    | Some(block) => self.retire(Some(block)),
+                    | None if self.filtered.remove(ptr) => {},
+                    | None => {
+                        self.unknown_frees.insert(*ptr);
+                    },
+                }
+                return Ok(());
+            },
+        };
+
+        let frames = match self.filter.keeps_size(size) {
+            | true => self.filter.frames(symbols),
+            | false => None,
+        };
+
+        match (event, frames) {
+            | (Event::Alloc { ptr, .. }, None) => {
+                let prev = self.leaked.remove(ptr);
+                self.retire(prev);
+                self.filtered.insert(*ptr);
+                return Ok(());
+            },
+            | (Event::Realloc { old_ptr, new_ptr, .. }, None) => {
+                // The block leaves the report as if it had been freed.
+                let prev = self.leaked.remove(old_ptr);
+                self.retire(prev);
+                self.filtered.remove(old_ptr);
+                self.filtered.insert(*new_ptr);
+                return Ok(());
+            },
+            | (
+                Event::Alloc {
+                    id,
+                    ptr,
+                    size,
+                    zeroed,
+                    region,
+                    ..
+                },
+                Some(frames),
+            ) => {
+                writeln!(
+                    out,
+                    "[ID: {}] ---------- Allocated {} bytes{} @ Address {:p} ----------",
+                    id,
+                    size,
+                    if *zeroed { " (zeroed)" } else { "" },
+                    *ptr as *const u8,
+                )?;
+
+                let block = Block {
+                    region: region.clone(),
+                    chain:  vec![Step {
+                        id:    *id,
+                        size:  *size,
+                        moved: false,
+                    }],
+                };
+                let prev = self.leaked.insert(*ptr, block);
+                self.retire(prev);
+                self.write_frames(out, &frames)?;
+            },
+            | (
+                Event::Realloc {
+                    id,
+                    old_ptr,
+                    new_ptr,
+                    old_size,
+                    new_size,
+                    region,
+                    ..
+                },
+                Some(frames),
+            ) => {
+                writeln!(
+                    out,
+                    "[ID: {}] ---------- Reallocated {} => {} bytes @ Address {:p} => {:p} ----------",
+                    id, old_size, new_size, *old_ptr as *const u8, *new_ptr as *const u8,
+                )?;
+
+                let step = Step {
+                    id:    *id,
+                    size:  *new_size,
+                    moved: old_ptr != new_ptr,
+                };
+                let block = match self.leaked.remove(old_ptr) {
+                    | Some(mut block) => {
+                        block.chain.push(step);
+                        block
+                    },
+                    | None => {
+                        // A block the filter dropped at its old size starts
+                        // a fresh chain.
+                        if !self.filtered.remove(old_ptr) {
+                            self.unknown_frees.insert(*old_ptr);
+                        }
+                        Block {
+                            region: region.clone(),
+                            chain:  vec![step],
+                        }
+                    },
+                };
+                let prev = self.leaked.insert(*new_ptr, block);
+                self.retire(prev);
+                self.write_frames(out, &frames)?;
+            },
+            | (Event::Free { .. }, _) => unreachable!("frees are handled above"),
+        }
+
+        Ok(())
+    }
+
+    fn write_frames<Writer: std::io::Write + ?Sized>(
+        &self,
+        out: &mut Writer,
+        frames: &[String],
+    ) -> std::io::Result<()> {
+        for frame in frames {
+            writeln!(out, "   > {frame}")?;
+        }
+
+        write!(out, "\n\n")
+    }
+
+    pub fn finish<Writer: std::io::Write + ?Sized>(mut self, out: &mut Writer) -> std::io::Result<()> {
+        if !self.leaked.is_empty() {
+            writeln!(out, "\n\n=============== POSSIBLE LEAKS ===============")?;
+            for (k, v) in self.leaked.iter() {
+                writeln!(
+                    out,
+                    "[ID: {}] => {} bytes @ address {:p} <{}>",
+                    v.id(),
+                    v.size(),
+                    *k as *const u8,
+                    v.region,
+                )?;
+            }
+
+            let mut by_region: HashMap<&str, (usize, usize)> = HashMap::new();
+            for v in self.leaked.values() {
+                let e = by_region.entry(&v.region).or_default();
+                e.0 += 1;
+                e.1 += v.size();
+            }
+
+            writeln!(out, "\n\n=============== LEAKS BY REGION ===============")?;
+            for (k, v) in by_region.iter() {
+                writeln!(out, "<{}> => {} allocations, {} bytes", k, v.0, v.1)?;
+            }
+        }
+
+        if !self.unknown_frees.is_empty() {
+            writeln!(out, "\n\n=============== UNKNOWN FREES ===============")?;
+            for p in self.unknown_frees.iter() {
+                writeln!(out, "  @ Address {:p}", *p as *const u8)?;
+            }
+        }
+
+        // Blocks still live at the end have chains too.
+        let live: Vec<Block> = self.leaked.drain().map(|(_, b)| b).collect();
+        for block in live {
+            self.retire(Some(block));
+        }
+
+        if !self.chains.is_empty() {
+            self.chains.sort_by_key(|c| c[0].id);
+
+            writeln!(out, "\n\n=============== GROWTH CHAINS ===============")?;
+            for chain in self.chains.iter() {
+                for (idx, step) in chain.iter().enumerate() {
+                    if idx > 0 {
+                        write!(out, " -> ")?;
+                    }
+                    write!(out, "[ID: {}] {} bytes", step.id, step.size)?;
+                    if idx > 0 {
+                        write!(out, " ({})", if step.moved { "moved" } else { "in place" })?;
+                    }
+                }
+                writeln!(out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Keeps the history of blocks that were resized at least once.
+    fn retire(&mut self, block: Option<Block>) {
+        if let Some(block) = block {
+            if block.chain.len() > 1 {
+                self.chains.push(block.chain);
+            }
+        }
+    }
+}
+
+
+//
+// Live allocation set at a point in time, grouped by call site. Snapshots
+// taken from the same run can be diffed to see what grew in between.
+//
+// Format:
+//   # sl-core snapshot v1
+//   snapshot <name>
+//   site <index>
+//   > <frame>
+//   live <id> <ptr> <size> <site>
+//
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub name:  String,
+    pub sites: Vec<Vec<String>>,
+    pub live:  Vec<LiveAlloc>,
+    index:     HashMap<Vec<String>, usize>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LiveAlloc {
+    pub id:   usize,
+    pub ptr:  usize,
+    pub size: usize,
+    pub site: usize,
+}
+
+impl Snapshot {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a live allocation, interning its call site.
+    pub fn add(&mut self, id: usize, ptr: usize, size: usize, frames: Vec<String>) {
+        let site = match self.index.get(&frames) {
+            | Some(site) => *site,
+            | None => {
+                self.index.insert(frames.clone(), self.sites.len());
+                self.sites.push(frames);
+                self.sites.len() - 1
+            },
+        };
+
+        self.live.push(LiveAlloc {
+            id,
+            ptr,
+            size,
+            site,
+        });
+    }
+
+    pub fn total_bytes(&self) -> usize { self.live.iter().map(|a| a.size).sum() }
+
+    /// Allocations live in `later` that were not live in this snapshot,
+    /// grouped by call site.
+    pub fn diff<'a>(&'a self, later: &'a Snapshot) -> SnapshotDiff<'a> {
+        let before: HashSet<usize> = self.live.iter().map(|a| a.id).collect();
+
+        let mut old_bytes: HashMap<&[String], usize> = HashMap::new();
+        for a in self.live.iter() {
+            *old_bytes.entry(&self.sites[a.site]).or_default() += a.size;
+        }
+
+        let mut sites: HashMap<&[String], SiteDiff> = HashMap::new();
+        for a in later.live.iter() {
+            let frames = &later.sites[a.site][..];
+            let site = sites.entry(frames).or_insert_with(|| SiteDiff {
+                frames,
+                new_count: 0,
+                new_bytes: 0,
+                growth: -(old_bytes.get(frames).copied().unwrap_or(0) as isize),
+            });
+
+            site.growth += a.size as isize;
+            if !before.contains(&a.id) {
+                site.new_count += 1;
+                site.new_bytes += a.size;
+            }
+        }
+
+        let mut sites: Vec<SiteDiff> = sites.into_values().filter(|s| s.new_count > 0).collect();
+        sites.sort_by_key(|s| std::cmp::Reverse(s.new_bytes));
+
+        SnapshotDiff {
+            from: self,
+            to: later,
+            sites,
+        }
+    }
+
+    pub fn write<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        writeln!(out, "{}", SNAPSHOT_HEADER)?;
+        writeln!(out, "snapshot {}", self.name)?;
+
+        for (idx, frames) in self.sites.iter().enumerate() {
+            writeln!(out, "site {}", idx)?;
+            for frame in frames {
+                writeln!(out, "> {}", frame)?;
+            }
+        }
+
+        for a in self.live.iter() {
+            writeln!(out, "live {} {:#x} {} {}", a.id, a.ptr, a.size, a.site)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: R) -> std::io::Result<Self> {
+        let mut snap = Snapshot::default();
+        let mut lines = input.lines();
+
+        match lines.next() {
+            | Some(Ok(l)) if l.trim_end() == SNAPSHOT_HEADER => {},
+            | _ => return Err(invalid("missing snapshot header")),
+        }
+
+        for line in lines {
+            let line = line?;
+            if let Some(frame) = line.strip_prefix("> ") {
+                snap.sites
+                    .last_mut()
+                    .ok_or_else(|| invalid("frame outside of a site"))?
+                    .push(frame.to_string());
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                | Some("snapshot") => snap.name = parts.collect::<Vec<_>>().join(" "),
+                | Some("site") => snap.sites.push(Vec::new()),
+                | Some("live") => {
+                    let a = LiveAlloc {
+                        id:   parse_dec(parts.next())?,
+                        ptr:  parse_hex(parts.next())? as usize,
+                        size: parse_dec(parts.next())?,
+                        site: parse_dec(parts.next())?,
+                    };
+                    if a.site >= snap.sites.len() {
+                        return Err(invalid("live allocation references an unknown site"));
+                    }
+                    snap.live.push(a);
+                },
+                | Some(s) if s.starts_with('#') => {},
+                | None => {},
+                | Some(s) => return Err(invalid(&format!("unknown record type '{}'", s))),
+            }
+        }
+
+        Ok(snap)
+    }
+}
+
+
+//
+// Difference between two snapshots
+//
+pub struct SnapshotDiff<'a> {
+    pub from:  &'a Snapshot,
+    pub to:    &'a Snapshot,
+    pub sites: Vec<SiteDiff<'a>>,
+}
+
+pub struct SiteDiff<'a> {
+    pub frames:    &'a [String],
+    pub new_count: usize,
+    pub new_bytes: usize,
+    pub growth:    isize,
+}
+
+impl SnapshotDiff<'_> {
+    pub fn write<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        let from_bytes = self.from.total_bytes();
+        let to_bytes = self.to.total_bytes();
+
+        writeln!(
+            out,
+            "=============== SNAPSHOT DIFF <{}> => <{}> ===============",
+            self.from.name, self.to.name,
+        )?;
+        writeln!(
+            out,
+            "Live (Allocations {} => {}, Bytes {} => {} ({:+}))\n",
+            self.from.live.len(),
+            self.to.live.len(),
+            from_bytes,
+            to_bytes,
+            to_bytes as isize - from_bytes as isize,
+        )?;
+
+        for site in self.sites.iter() {
+            writeln!(
+                out,
+                "---------- {} new allocations, {} new bytes, growth {:+} bytes ----------",
+                site.new_count, site.new_bytes, site.growth,
+            )?;
+            for frame in site.frames {
+                writeln!(out, "   > {frame}")?;
+            }
+            write!(out, "\n\n")?;
+        }
+
+        Ok(())
+    }
+}
+
+
+//
+// Layout information pulled from an ELF object on disk
+//
+#[derive(Clone, Debug, Default)]
+pub struct ObjectInfo {
+    pub build_id: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub offset: u64,
+    pub vaddr:  u64,
+    pub size:   u64,
+}
+
+impl ObjectInfo {
+    /// Reads the build ID and loadable segments of a 64-bit little-endian
+    /// ELF file. Returns `None` for anything else.
+    pub fn read<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        if data.get(0..6)? != b"\x7fELF\x02\x01" {
+            return None;
+        }
+
+        let phoff = read_u64(&data, 0x20)? as usize;
+        let phentsize = read_u16(&data, 0x36)? as usize;
+        let phnum = read_u16(&data, 0x38)? as usize;
+
+        // Every offset and size comes from the file; a malformed one fails
+        // the read instead of overflowing.
+        let mut info = ObjectInfo::default();
+        for i in 0..phnum {
+            let ph = phoff.checked_add(i.checked_mul(phentsize)?)?;
+            let p_type = read_u32(&data, ph)?;
+            let p_offset = read_u64(&data, ph.checked_add(0x08)?)?;
+            let p_vaddr = read_u64(&data, ph.checked_add(0x10)?)?;
+            let p_filesz = read_u64(&data, ph.checked_add(0x20)?)?;
+
+            match p_type {
+                | PT_LOAD => info.segments.push(Segment {
+                    offset: p_offset,
+                    vaddr:  p_vaddr,
+                    size:   p_filesz,
+                }),
+                | PT_NOTE if info.build_id.is_none() => {
+                    let notes = bytes(&data, usize::try_from(p_offset).ok()?, usize::try_from(p_filesz).ok()?)?;
+                    info.build_id = find_build_id(notes);
+                },
+                | _ => {},
+            }
+        }
+
+        Some(info)
+    }
+
+    /// Virtual address (as seen by the debug info) of a file offset.
+    pub fn vaddr_of(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|s| offset >= s.offset && offset - s.offset < s.size)
+            .and_then(|s| (offset - s.offset).checked_add(s.vaddr))
+    }
+}
+
+
+/// Executable mappings of the current process along with their build IDs.
+#[cfg(target_os = "linux")]
+pub fn loaded_modules() -> Vec<Module> {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap_or_default();
+    let mut ids: HashMap<&str, Option<String>> = HashMap::new();
+
+    maps.lines()
+        .filter_map(|line| {
+            // start-end perms offset dev inode path
+            let mut parts = line.split_whitespace();
+            let (start, end) = parts.next()?.split_once('-')?;
+            let perms = parts.next()?;
+            let offset = parts.next()?;
+            let path = parts.nth(2)?;
+
+            if !perms.contains('x') || !path.starts_with('/') {
+                return None;
+            }
+
+            let build_id = ids
+                .entry(path)
+                .or_insert_with(|| ObjectInfo::read(path).and_then(|i| i.build_id))
+                .clone();
+
+            Some(Module {
+                start: u64::from_str_radix(start, 16).ok()?,
+                end: u64::from_str_radix(end, 16).ok()?,
+                offset: u64::from_str_radix(offset, 16).ok()?,
+                build_id,
+                path: path.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn loaded_modules() -> Vec<Module> { Vec::new() }
+
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+
+
+fn find_build_id(mut notes: &[u8]) -> Option<String> {
+    while notes.len() >= 12 {
+        let namesz = read_u32(notes, 0)? as usize;
+        let descsz = read_u32(notes, 4)? as usize;
+        let n_type = read_u32(notes, 8)?;
+
+        let name_start: usize = 12;
+        let desc_start = name_start.checked_add(align4(namesz)?)?;
+        let next = desc_start.checked_add(align4(descsz)?)?;
+
+        if n_type == NT_GNU_BUILD_ID && bytes(notes, name_start, namesz)? == b"GNU\0" {
+            let desc = bytes(notes, desc_start, descsz)?;
+            return Some(desc.iter().map(|b| format!("{:02x}", b)).collect());
+        }
+
+        notes = notes.get(next..)?;
+    }
+
+    None
+}
+
+fn align4(v: usize) -> Option<usize> { Some(v.checked_add(3)? & !3) }
+
+fn bytes(data: &[u8], at: usize, len: usize) -> Option<&[u8]> { data.get(at..at.checked_add(len)?) }
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> { Some(u16::from_le_bytes(bytes(data, at, 2)?.try_into().ok()?)) }
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> { Some(u32::from_le_bytes(bytes(data, at, 4)?.try_into().ok()?)) }
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> { Some(u64::from_le_bytes(bytes(data, at, 8)?.try_into().ok()?)) }
+
+fn parse_site<'a>(mut parts: impl Iterator<Item = &'a str>) -> std::io::Result<(String, Vec<u64>)> {
+    let region = match parts.next().ok_or_else(|| invalid("record is truncated"))? {
+        | "-" => String::new(),
+        | r => r.to_string(),
+    };
+    let ips = parts
+        .map(|p| parse_hex(Some(p)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    Ok((region, ips))
+}
+
+fn parse_hex(v: Option<&str>) -> std::io::Result<u64> {
+    let v = v.ok_or_else(|| invalid("record is truncated"))?;
+    u64::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| invalid("bad hex value"))
+}
+
+fn parse_dec(v: Option<&str>) -> std::io::Result<usize> {
+    let v = v.ok_or_else(|| invalid("record is truncated"))?;
+    v.parse().map_err(|_| invalid("bad decimal value"))
+}
+
+fn invalid(msg: &str) -> Error { Error::new(ErrorKind::InvalidData, msg.to_string()) }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn raw_dump_round_trip() {
+        let module = Module {
+            start:    0x5555_0000,
+            end:      0x5556_0000,
+            offset:   0x1000,
+            build_id: Some("abcdef".to_string()),
+            path:     "/opt/my app/bin".to_string(),
+        };
+        let events = [
+            Event::Alloc {
+                id:     0,
+                ptr:    0x1000,
+                size:   64,
+                align:  8,
+                zeroed: true,
+                region: "parse input".to_string(),
+                ips:    vec![0x5555_0010, 0x5555_0020],
+            },
+            Event::Realloc {
+                id:       1,
+                old_ptr:  0x1000,
+                new_ptr:  0x2000,
+                old_size: 64,
+                new_size: 128,
+                align:    8,
+                region:   "none".to_string(),
+                ips:      vec![],
+            },
+            Event::Free { ptr: 0x2000 },
+        ];
+
+        let mut out = Vec::new();
+        write_raw_header(&mut out).unwrap();
+        write_raw_module(&mut out, &module).unwrap();
+        write_raw_hide(&mut out, "my_shim::").unwrap();
+        for e in events.iter() {
+            e.write_raw(&mut out).unwrap();
+        }
+
+        let dump = RawDump::read(&out[..]).unwrap();
+        assert_eq!(dump.modules.len(), 1);
+        assert_eq!(dump.modules[0].start, module.start);
+        assert_eq!(dump.modules[0].end, module.end);
+        assert_eq!(dump.modules[0].offset, module.offset);
+        assert_eq!(dump.modules[0].build_id, module.build_id);
+        assert_eq!(dump.modules[0].path, module.path);
+        assert_eq!(dump.hide, ["my_shim::"]);
+
+        assert_eq!(dump.events.len(), events.len());
+        match &dump.events[0] {
+            | Event::Alloc {
+                id,
+                ptr,
+                size,
+                align,
+                zeroed,
+                region,
+                ips,
+            } => {
+                assert_eq!((*id, *ptr, *size, *align, *zeroed), (0, 0x1000, 64, 8, true));
+                // Whitespace in region names can't survive the line format.
+                assert_eq!(region, "parse_input");
+                assert_eq!(ips, &[0x5555_0010, 0x5555_0020]);
+            },
+            | e => panic!("expected an alloc, got {:?}", e),
+        }
+        match &dump.events[1] {
+            | Event::Realloc {
+                id,
+                old_ptr,
+                new_ptr,
+                old_size,
+                new_size,
+                align,
+                region,
+                ips,
+            } => {
+                assert_eq!(
+                    (*id, *old_ptr, *new_ptr, *old_size, *new_size, *align),
+                    (1, 0x1000, 0x2000, 64, 128, 8)
+                );
+                assert_eq!(region, "none");
+                assert!(ips.is_empty());
+            },
+            | e => panic!("expected a realloc, got {:?}", e),
+        }
+        assert!(matches!(dump.events[2], Event::Free { ptr: 0x2000 }));
+    }
+
+    #[test]
+    fn raw_dump_round_trips_unnamed_regions() {
+        let event = Event::Alloc {
+            id:     3,
+            ptr:    0x3000,
+            size:   16,
+            align:  8,
+            zeroed: false,
+            region: String::new(),
+            ips:    vec![0x5555_0030],
+        };
+
+        let mut out = Vec::new();
+        write_raw_header(&mut out).unwrap();
+        event.write_raw(&mut out).unwrap();
+
+        let dump = RawDump::read(&out[..]).unwrap();
+        match &dump.events[..] {
+            | [Event::Alloc { id, region, ips, .. }] => {
+                assert_eq!(*id, 3);
+                assert_eq!(region, "");
+                assert_eq!(ips, &[0x5555_0030]);
+            },
+            | e => panic!("expected one alloc, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn object_info_rejects_malformed_elf() {
+        // Program headers claimed at the very end of the address space.
+        let mut elf = vec![0u8; 64];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&4u16.to_le_bytes());
+
+        let path = std::env::temp_dir().join(format!("sl-core-bad-elf-{}", std::process::id()));
+        std::fs::write(&path, &elf).unwrap();
+        let info = ObjectInfo::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(info.is_none());
+    }
+
+    #[test]
+    fn raw_dump_rejects_bad_input() {
+        assert!(RawDump::read(&b"alloc 0 0x10 1 1 -\n"[..]).is_err());
+
+        let truncated = format!("{}\nalloc 0 0x10 1\n", RAW_HEADER);
+        assert!(RawDump::read(truncated.as_bytes()).is_err());
+
+        let unknown = format!("{}\nmalloc 0 0x10 1 1 -\n", RAW_HEADER);
+        assert!(RawDump::read(unknown.as_bytes()).is_err());
+    }
+
+    fn frames(names: &[&str]) -> Vec<String> { names.iter().map(|n| n.to_string()).collect() }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut snap = Snapshot::new("after load");
+        snap.add(1, 0x1000, 32, frames(&["app::load @ line 10", "app::main @ line 3"]));
+        snap.add(2, 0x2000, 64, frames(&["app::cache @ line 7"]));
+        snap.add(3, 0x3000, 16, frames(&["app::load @ line 10", "app::main @ line 3"]));
+
+        let mut out = Vec::new();
+        snap.write(&mut out).unwrap();
+        let read = Snapshot::read(&out[..]).unwrap();
+
+        assert_eq!(read.name, "after load");
+        assert_eq!(read.sites, snap.sites);
+        assert_eq!(read.sites.len(), 2);
+        assert_eq!(read.total_bytes(), 112);
+
+        let live: Vec<_> = read.live.iter().map(|a| (a.id, a.ptr, a.size, a.site)).collect();
+        assert_eq!(live, [(1, 0x1000, 32, 0), (2, 0x2000, 64, 1), (3, 0x3000, 16, 0)]);
+    }
+
+    #[test]
+    fn snapshot_rejects_bad_input() {
+        let frame = format!("{}\n> app::main @ line 3\n", SNAPSHOT_HEADER);
+        assert!(Snapshot::read(frame.as_bytes()).is_err());
+
+        let site = format!("{}\nsite 0\nlive 1 0x10 8 1\n", SNAPSHOT_HEADER);
+        assert!(Snapshot::read(site.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn snapshot_diff_growth() {
+        let load = frames(&["app::load @ line 10"]);
+        let cache = frames(&["app::cache @ line 7"]);
+        let temp = frames(&["app::temp @ line 2"]);
+
+        let mut before = Snapshot::new("start");
+        before.add(1, 0x1000, 100, load.clone());
+        before.add(2, 0x2000, 50, cache.clone());
+        before.add(3, 0x3000, 30, temp.clone());
+
+        // `load` keeps its block and adds one, `cache` swaps its block for a
+        // smaller one and `temp` goes away entirely.
+        let mut after = Snapshot::new("end");
+        after.add(1, 0x1000, 100, load.clone());
+        after.add(4, 0x4000, 200, load);
+        after.add(5, 0x5000, 20, cache);
+
+        let diff = before.diff(&after);
+        let sites: Vec<_> = diff
+            .sites
+            .iter()
+            .map(|s| (s.frames[0].as_str(), s.new_count, s.new_bytes, s.growth))
+            .collect();
+        assert_eq!(sites, [("app::load @ line 10", 1, 200, 200), ("app::cache @ line 7", 1, 20, -30)]);
+
+        let mut out = Vec::new();
+        diff.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("SNAPSHOT DIFF <start> => <end>"));
+        assert!(out.contains("Live (Allocations 3 => 3, Bytes 180 => 320 (+140))"));
+        assert!(out.contains("1 new allocations, 200 new bytes, growth +200 bytes"));
+        assert!(out.contains("1 new allocations, 20 new bytes, growth -30 bytes"));
+        assert!(out.find("app::load").unwrap() < out.find("app::cache").unwrap());
+        assert!(!out.contains("app::temp"));
+    }
+}
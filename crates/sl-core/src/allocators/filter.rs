@@ -0,0 +1,268 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/filter
+ *
+ * Purpose:
+ *    Filter policy deciding which allocations are worth recording and how
+ *    their stacks are shown. The same policy is applied by the `Tracing`
+ *    allocator while capturing and again by every report built from the
+ *    captured data (including offline reports from `sl-symbolize`).
+ *
+ *    Call-site rules match crate / module path prefixes against every
+ *    frame of an allocation's stack:
+ *      - `include`: when not empty, only allocations with at least one
+ *        frame under one of these paths are kept.
+ *      - `exclude`: allocations with any frame under one of these paths
+ *        are dropped.
+ *
+ *    Frame rules only affect which frames are shown for kept allocations.
+ *    Allocator internals are always hidden; `hide` adds more symbol
+ *    prefixes (e.g. a shim's own frames, see the raw dump's `hide`
+ *    records).
+ *
+ */
+
+use super::dump::Symbol;
+
+
+const UNKNOWN: &str = "<unknown>";
+const IGNORED_SYMBOLS: [&str; 10] = [
+    "_main",
+    "__rg_alloc",
+    "__rg_realloc",
+    "__rust_alloc",
+    "__rust_alloc_zeroed",
+    "__rust_dealloc",
+    "__rust_realloc",
+    "backtrace::",
+    "<sl_core::allocators::",
+    "sl_core::allocators::",
+];
+const REGISTRY_PATHS: [&str; 2] = ["/.cargo/registry/", "/.cargo/git/"];
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterPolicy {
+    // Allocation sizes to record (inclusive)
+    pub min_size:        usize,
+    pub max_size:        usize,
+    // Crate / module path prefixes (e.g. "my_crate::cache")
+    pub include:         Vec<String>,
+    pub exclude:         Vec<String>,
+    // Frames shown per stack, after every other frame rule
+    pub max_depth:       usize,
+    // Drop frames from std (sources under /rustc)
+    pub filter_std:      bool,
+    // Drop frames from cargo registry / git dependencies
+    pub filter_registry: bool,
+    // Extra symbol prefixes to drop, on top of the allocator internals
+    pub hide:            Vec<String>,
+}
+
+impl FilterPolicy {
+    /// Records everything, hiding std frames.
+    pub const fn new() -> Self {
+        Self {
+            min_size:        0,
+            max_size:        usize::MAX,
+            include:         Vec::new(),
+            exclude:         Vec::new(),
+            max_depth:       usize::MAX,
+            filter_std:      true,
+            filter_registry: false,
+            hide:            Vec::new(),
+        }
+    }
+
+    /// Records everything, std frames included.
+    pub const fn with_std() -> Self {
+        let mut policy = Self::new();
+        policy.filter_std = false;
+        policy
+    }
+
+    #[inline]
+    pub fn keeps_size(&self, size: usize) -> bool { size >= self.min_size && size <= self.max_size }
+
+    /// True when the call-site rules need symbol names at all.
+    #[inline]
+    pub fn has_site_rules(&self) -> bool { !self.include.is_empty() || !self.exclude.is_empty() }
+
+    /// Returns (included, excluded) for a single symbol name.
+    pub fn match_symbol(&self, name: &str) -> (bool, bool) {
+        let path = name.trim_start_matches('<');
+        let matches = |patterns: &[String]| patterns.iter().any(|p| path.starts_with(p.as_str()));
+
+        (matches(&self.include), matches(&self.exclude))
+    }
+
+    /// Applies the call-site rules to a fully resolved stack.
+    pub fn keeps_site(&self, symbols: &[Symbol]) -> bool {
+        if !self.has_site_rules() {
+            return true;
+        }
+
+        let (mut included, mut excluded) = (self.include.is_empty(), false);
+        for name in symbols.iter().filter_map(|s| s.name.as_deref()) {
+            let (inc, exc) = self.match_symbol(name);
+            included |= inc;
+            excluded |= exc;
+        }
+
+        included && !excluded
+    }
+
+    /// Formats a stack the way it appears in reports, or `None` if the
+    /// call site is filtered out.
+    pub fn frames(&self, symbols: &[Symbol]) -> Option<Vec<String>> {
+        if !self.keeps_site(symbols) {
+            return None;
+        }
+
+        Some(self.format_frames(symbols))
+    }
+
+    /// Formats a stack under the frame rules only (the call-site rules are
+    /// not consulted).
+    pub fn format_frames(&self, symbols: &[Symbol]) -> Vec<String> {
+        symbols
+            .iter()
+            .filter(|sym| self.keeps_frame(sym))
+            .filter_map(|sym| {
+                let sym_name = sym.name.as_deref().unwrap_or(UNKNOWN);
+                if IGNORED_SYMBOLS
+                    .iter()
+                    .any(|sym| sym_name.starts_with(sym) || sym_name.ends_with(sym))
+                {
+                    return None;
+                }
+                let path = sym_name.trim_start_matches('<');
+                if self.hide.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+                    return None;
+                }
+
+                let line_number = sym.lineno.unwrap_or(u32::MAX);
+                Some(format!("{sym_name} @ line {line_number}"))
+            })
+            .take(self.max_depth)
+            .collect()
+    }
+
+    fn keeps_frame(&self, sym: &Symbol) -> bool {
+        let Some(file) = sym.filename.as_deref() else {
+            return true;
+        };
+
+        let std = self.filter_std && file.starts_with("/rustc");
+        let registry = self.filter_registry && REGISTRY_PATHS.iter().any(|p| file.contains(p));
+
+        !(std || registry)
+    }
+}
+
+impl Default for FilterPolicy {
+    fn default() -> Self { Self::new() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn symbol(name: &str, filename: &str, lineno: u32) -> Symbol {
+        Symbol {
+            name:     Some(name.to_string()),
+            filename: Some(filename.to_string()),
+            lineno:   Some(lineno),
+        }
+    }
+
+    fn stack() -> Vec<Symbol> {
+        vec![
+            symbol("__rust_alloc", "/rustc/abc/library/alloc/src/alloc.rs", 10),
+            symbol("alloc::vec::Vec<T>::push", "/rustc/abc/library/alloc/src/vec/mod.rs", 20),
+            symbol("serde_json::de::parse", "/home/me/.cargo/registry/src/serde_json/de.rs", 30),
+            symbol("<app::cache::Cache as Drop>::drop", "/src/app/cache.rs", 40),
+            symbol("app::main", "/src/app/main.rs", 50),
+        ]
+    }
+
+    #[test]
+    fn size_rules() {
+        let policy = FilterPolicy {
+            min_size: 16,
+            max_size: 64,
+            ..FilterPolicy::new()
+        };
+
+        assert!(!policy.keeps_size(15));
+        assert!(policy.keeps_size(16));
+        assert!(policy.keeps_size(64));
+        assert!(!policy.keeps_size(65));
+    }
+
+    #[test]
+    fn site_rules() {
+        let stack = stack();
+        assert!(!FilterPolicy::new().has_site_rules());
+        assert!(FilterPolicy::new().keeps_site(&stack));
+
+        let mut policy = FilterPolicy::new();
+        policy.include.push("app::cache".to_string());
+        assert_eq!(policy.match_symbol("<app::cache::Cache as Drop>::drop"), (true, false));
+        assert!(policy.keeps_site(&stack));
+        assert!(!policy.keeps_site(&stack[..3]));
+
+        policy.exclude.push("serde_json".to_string());
+        assert!(!policy.keeps_site(&stack));
+        assert!(policy.frames(&stack).is_none());
+        assert!(policy.keeps_site(&stack[3..]));
+    }
+
+    #[test]
+    fn frame_rules() {
+        let stack = stack();
+
+        assert_eq!(
+            FilterPolicy::new().format_frames(&stack),
+            [
+                "serde_json::de::parse @ line 30",
+                "<app::cache::Cache as Drop>::drop @ line 40",
+                "app::main @ line 50",
+            ]
+        );
+        assert_eq!(
+            FilterPolicy::with_std().format_frames(&stack),
+            [
+                "alloc::vec::Vec<T>::push @ line 20",
+                "serde_json::de::parse @ line 30",
+                "<app::cache::Cache as Drop>::drop @ line 40",
+                "app::main @ line 50",
+            ]
+        );
+
+        let policy = FilterPolicy {
+            max_depth: 1,
+            filter_registry: true,
+            ..FilterPolicy::new()
+        };
+        assert_eq!(
+            policy.format_frames(&stack),
+            ["<app::cache::Cache as Drop>::drop @ line 40"]
+        );
+
+        let policy = FilterPolicy {
+            hide: vec!["serde_json::".to_string()],
+            ..FilterPolicy::new()
+        };
+        assert_eq!(
+            policy.format_frames(&stack),
+            ["<app::cache::Cache as Drop>::drop @ line 40", "app::main @ line 50"]
+        );
+        assert_eq!(FilterPolicy::default(), FilterPolicy::new());
+    }
+}
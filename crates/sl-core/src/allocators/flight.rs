@@ -0,0 +1,232 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/flight
+ *
+ * Purpose:
+ *    Flight recorder tracker. Keeps only the last `N` memory operations
+ *    (thread, timestamp and optionally a short stack) in a ring that is
+ *    part of the tracker itself, so nothing is ever allocated after
+ *    construction and memory use stays constant for the life of the
+ *    process. Dumping it shows the final moments of allocation activity,
+ *    e.g. from a panic hook.
+ *
+ *    The ring lives inline; build it in a `static`, or with `boxed` to
+ *    put it straight on the heap, rather than on the stack when `N` is
+ *    large.
+ *
+ */
+
+use std::{
+    alloc::Layout,
+    sync::OnceLock,
+    time::Instant,
+};
+
+use super::{
+    filter::FilterPolicy,
+    recorder::thread_no,
+    stacks,
+    tracker,
+    Tracker,
+};
+
+
+// Room for the allocator's own frames plus a useful number of caller
+// frames; the filter's depth is clamped to this.
+const STACK_DEPTH: usize = tracker::ALLOC_FRAMES + 32;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Alloc,
+    AllocZeroed,
+    Realloc,
+    Free,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    op:       Op,
+    // Allocation sequence number (unused for frees)
+    id:       usize,
+    thread:   usize,
+    nanos:    u64,
+    ptr:      usize,
+    size:     usize,
+    old_ptr:  usize,
+    old_size: usize,
+    depth:    usize,
+    stack:    [usize; STACK_DEPTH],
+}
+
+impl Entry {
+    const EMPTY: Self = Self {
+        op:       Op::Alloc,
+        id:       0,
+        thread:   0,
+        nanos:    0,
+        ptr:      0,
+        size:     0,
+        old_ptr:  0,
+        old_size: 0,
+        depth:    0,
+        stack:    [0; STACK_DEPTH],
+    };
+}
+
+
+pub struct FlightRecorder<const N: usize> {
+    ring:   [Entry; N],
+    depth:  usize,
+    // Total number of events seen; the next slot is `count % N`
+    count:  usize,
+    stacks: bool,
+}
+
+impl<const N: usize> FlightRecorder<N> {
+    pub const fn new() -> Self { Self::build(false) }
+
+    /// Also captures the innermost frames of every operation.
+    pub const fn with_stacks() -> Self { Self::build(true) }
+
+    /// `new`, built in place on the heap.
+    pub fn boxed() -> Box<Self> { Self::build_boxed(false) }
+
+    /// `with_stacks`, built in place on the heap.
+    pub fn boxed_with_stacks() -> Box<Self> { Self::build_boxed(true) }
+
+    const fn build(stacks: bool) -> Self {
+        assert!(N > 0, "flight recorder needs at least one slot");
+
+        Self {
+            ring: [Entry::EMPTY; N],
+            depth: STACK_DEPTH,
+            count: 0,
+            stacks,
+        }
+    }
+
+    // Going through `build` would put the whole ring on the stack first.
+    fn build_boxed(stacks: bool) -> Box<Self> {
+        assert!(N > 0, "flight recorder needs at least one slot");
+
+        let mut this = Box::<Self>::new_uninit();
+        let ptr = this.as_mut_ptr();
+        // SAFETY: every field is written through `ptr` before the box is
+        // assumed initialized.
+        unsafe {
+            let ring = std::ptr::addr_of_mut!((*ptr).ring).cast::<Entry>();
+            for idx in 0..N {
+                ring.add(idx).write(Entry::EMPTY);
+            }
+            std::ptr::addr_of_mut!((*ptr).depth).write(STACK_DEPTH);
+            std::ptr::addr_of_mut!((*ptr).count).write(0);
+            std::ptr::addr_of_mut!((*ptr).stacks).write(stacks);
+            this.assume_init()
+        }
+    }
+
+    fn record(&mut self, op: Op, id: usize, ptr: *mut u8, size: usize, old_ptr: *mut u8, old_size: usize) {
+        let start = START.get_or_init(Instant::now);
+
+        let entry = &mut self.ring[self.count % N];
+        self.count += 1;
+
+        entry.op = op;
+        entry.id = id;
+        entry.thread = thread_no();
+        entry.nanos = start.elapsed().as_nanos() as u64;
+        entry.ptr = ptr as usize;
+        entry.size = size;
+        entry.old_ptr = old_ptr as usize;
+        entry.old_size = old_size;
+        entry.depth = 0;
+
+        if self.stacks {
+            let depth = self.depth;
+            backtrace::trace(|frame| {
+                entry.stack[entry.depth] = frame.ip() as usize;
+                entry.depth += 1;
+                entry.depth < depth
+            });
+        }
+    }
+}
+
+impl<const N: usize> Default for FlightRecorder<N> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const N: usize> Tracker for FlightRecorder<N> {
+    fn dump_info(
+        &mut self,
+        out: &mut dyn std::io::Write,
+        filter: &FilterPolicy,
+    ) -> std::io::Result<()> {
+        let shown = self.count.min(N);
+
+        writeln!(out, "=============== FLIGHT RECORDER ===============")?;
+        writeln!(out, "{} operations recorded, showing the last {}\n", self.count, shown)?;
+
+        for idx in self.count - shown..self.count {
+            let e = &self.ring[idx % N];
+
+            // Call-site rules can only be applied to entries with a stack.
+            let frames = match e.depth {
+                | 0 => Some(Vec::new()),
+                | depth => filter.frames(&stacks::resolve_ips(&e.stack[..depth])),
+            };
+            let Some(frames) = frames.filter(|_| filter.keeps_size(e.size)) else {
+                continue;
+            };
+
+            write!(out, "[{:>14.6} ms] thread {:<3} ", e.nanos as f64 / 1e6, e.thread)?;
+            match e.op {
+                | Op::Alloc => writeln!(out, "alloc   {} bytes @ {:p} [ID: {}]", e.size, e.ptr as *const u8, e.id)?,
+                | Op::AllocZeroed => writeln!(out, "zalloc  {} bytes @ {:p} [ID: {}]", e.size, e.ptr as *const u8, e.id)?,
+                | Op::Realloc => writeln!(
+                    out,
+                    "realloc {} => {} bytes @ {:p} => {:p} [ID: {}]",
+                    e.old_size, e.size, e.old_ptr as *const u8, e.ptr as *const u8, e.id
+                )?,
+                | Op::Free => writeln!(out, "free    {} bytes @ {:p}", e.size, e.ptr as *const u8)?,
+            }
+
+            for frame in frames {
+                writeln!(out, "   > {frame}")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_filter(&mut self, filter: &FilterPolicy) { self.depth = tracker::capture_depth(filter).min(STACK_DEPTH); }
+
+    fn track_alloc(&mut self, id: usize, ptr: *mut u8, layout: Layout) {
+        self.record(Op::Alloc, id, ptr, layout.size(), std::ptr::null_mut(), 0);
+    }
+
+    fn track_alloc_zeroed(&mut self, id: usize, ptr: *mut u8, layout: Layout) {
+        self.record(Op::AllocZeroed, id, ptr, layout.size(), std::ptr::null_mut(), 0);
+    }
+
+    fn track_realloc(
+        &mut self,
+        id: usize,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) {
+        self.record(Op::Realloc, id, new_ptr, new_layout.size(), old_ptr, old_layout.size());
+    }
+
+    fn track_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.record(Op::Free, 0, ptr, layout.size(), std::ptr::null_mut(), 0);
+    }
+}
@@ -0,0 +1,218 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/future
+ *
+ * Purpose:
+ *    Attributes allocations to a future across all of its polls, no matter
+ *    which thread ends up driving it. While a wrapped future is being polled
+ *    the polling thread points a thread-local at the future's counters and
+ *    the allocators record into it.
+ *
+ *    Nested wrappers work as expected; anything counted by an inner future
+ *    is also rolled up into the enclosing one.
+ *
+ */
+
+use std::{
+    cell::Cell,
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+
+thread_local! {
+    static CURRENT: Cell<*const Cell<AllocCounts>> = const { Cell::new(std::ptr::null()) };
+}
+
+
+//
+// Totals gathered while polling a single future
+//
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllocCounts {
+    pub allocs:      usize,
+    pub frees:       usize,
+    pub bytes_alloc: usize,
+    pub bytes_freed: usize,
+    pub polls:       usize,
+}
+
+impl AllocCounts {
+    pub fn active(&self) -> isize { self.allocs as isize - self.frees as isize }
+
+    pub fn active_bytes(&self) -> isize { self.bytes_alloc as isize - self.bytes_freed as isize }
+
+    pub(super) fn delta(&self, since: &AllocCounts) -> AllocCounts {
+        AllocCounts {
+            allocs:      self.allocs - since.allocs,
+            frees:       self.frees - since.frees,
+            bytes_alloc: self.bytes_alloc - since.bytes_alloc,
+            bytes_freed: self.bytes_freed - since.bytes_freed,
+            polls:       0,
+        }
+    }
+
+    pub(super) fn merge(&mut self, other: &AllocCounts) {
+        self.allocs += other.allocs;
+        self.frees += other.frees;
+        self.bytes_alloc += other.bytes_alloc;
+        self.bytes_freed += other.bytes_freed;
+    }
+}
+
+
+//
+// Future wrapper that owns the counters and hands them to the allocators
+// for the duration of each poll.
+//
+pub struct CountAllocs<F, R>
+where
+    F: Future,
+    R: FnOnce(AllocCounts),
+{
+    inner:  F,
+    counts: Cell<AllocCounts>,
+    report: Option<R>,
+}
+
+impl<F, R> Future for CountAllocs<F, R>
+where
+    F: Future,
+    R: FnOnce(AllocCounts),
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `inner` is never moved out of the pinned wrapper and the
+        // remaining fields are not structurally pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        let before = this.counts.get();
+        let res = {
+            let _scope = Scope::enter(&this.counts);
+            inner.poll(cx)
+        };
+
+        let mut counts = this.counts.get();
+        counts.polls += 1;
+        this.counts.set(counts);
+
+        // Roll everything from this poll up into whoever is polling us.
+        let delta = counts.delta(&before);
+        with_current(|parent| {
+            let mut pc = parent.get();
+            pc.merge(&delta);
+            parent.set(pc);
+        });
+
+        if res.is_ready() {
+            if let Some(report) = this.report.take() {
+                report(counts);
+            }
+        }
+
+        res
+    }
+}
+
+
+//
+// Extension methods for wrapping any future
+//
+pub trait CountAllocsExt: Future + Sized {
+    /// Logs the allocation totals under `tag` once the future completes.
+    fn count_allocs<T>(self, tag: T) -> CountAllocs<Self, impl FnOnce(AllocCounts)>
+    where
+        T: Display,
+    {
+        self.count_allocs_with(move |c| {
+            log::info!(
+                "<{}> Allocations (Total {}, Active {}, Bytes {} => Active {}, Polls {})",
+                tag,
+                c.allocs,
+                c.active(),
+                c.bytes_alloc,
+                c.active_bytes(),
+                c.polls,
+            );
+        })
+    }
+
+    /// Hands the allocation totals to `report` once the future completes.
+    fn count_allocs_with<R>(self, report: R) -> CountAllocs<Self, R>
+    where
+        R: FnOnce(AllocCounts),
+    {
+        CountAllocs {
+            inner:  self,
+            counts: Cell::new(AllocCounts::default()),
+            report: Some(report),
+        }
+    }
+}
+
+impl<F> CountAllocsExt for F where F: Future {}
+
+
+//
+// Hooks called from the allocators
+//
+#[inline]
+pub(crate) fn record_alloc(size: usize) {
+    with_current(|c| {
+        let mut counts = c.get();
+        counts.allocs += 1;
+        counts.bytes_alloc += size;
+        c.set(counts);
+    });
+}
+
+#[inline]
+pub(crate) fn record_dealloc(size: usize) {
+    with_current(|c| {
+        let mut counts = c.get();
+        counts.frees += 1;
+        counts.bytes_freed += size;
+        c.set(counts);
+    });
+}
+
+
+#[inline]
+fn with_current<F: FnOnce(&Cell<AllocCounts>)>(f: F) {
+    let current = CURRENT.try_with(|c| c.get()).unwrap_or(std::ptr::null());
+    if !current.is_null() {
+        // SAFETY: only ever points at counters owned by a future that is
+        // currently being polled on this thread (see `Scope`).
+        f(unsafe { &*current });
+    }
+}
+
+
+//
+// Points the current thread at a set of counters until dropped
+//
+struct Scope {
+    prev: *const Cell<AllocCounts>,
+}
+
+impl Scope {
+    fn enter(counts: &Cell<AllocCounts>) -> Self {
+        Self {
+            prev: CURRENT.with(|c| c.replace(counts)),
+        }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) { CURRENT.with(|c| c.set(self.prev)); }
+}
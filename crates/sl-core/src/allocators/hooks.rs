@@ -0,0 +1,105 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/hooks
+ *
+ * Purpose:
+ *    Common entry points called by every wrapper allocator for each
+ *    allocation / de-allocation. Anything that needs to observe memory
+ *    operations regardless of which global allocator is installed hangs
+ *    off of here.
+ *
+ */
+
+use std::alloc::Layout;
+
+#[cfg(all(feature = "alloc-shm", target_os = "linux"))]
+use super::shm;
+#[cfg(feature = "alloc-spans")]
+use super::spans;
+use super::{
+    chrome,
+    future,
+    no_alloc,
+    region,
+    timeline,
+    watermark,
+};
+
+
+cfg_alloc_count! {
+    #[inline]
+    pub(crate) fn on_alloc(layout: Layout) {
+        observe_alloc(layout);
+        record_alloc(layout);
+    }
+
+    #[inline]
+    pub(crate) fn on_dealloc(layout: Layout) {
+        observe_dealloc(layout);
+        record_dealloc(layout);
+    }
+
+    #[inline]
+    pub(crate) fn on_realloc(old_layout: Layout, new_layout: Layout) {
+        observe_realloc(old_layout, new_layout);
+        record_realloc(old_layout, new_layout);
+    }
+}
+
+//
+// Counters only. Lock free and never allocate, so safe to call for every
+// block, including ones a wrapper allocates for itself while holding its
+// own locks (which keeps allocs and frees paired up).
+//
+#[inline]
+pub(crate) fn record_alloc(layout: Layout) {
+    future::record_alloc(layout.size());
+    region::record_alloc(layout.size());
+    watermark::record_alloc(layout.size());
+    timeline::record_alloc();
+    #[cfg(all(feature = "alloc-shm", target_os = "linux"))]
+    shm::record_alloc();
+    #[cfg(feature = "alloc-spans")]
+    spans::record_alloc(layout.size());
+}
+
+#[inline]
+pub(crate) fn record_dealloc(layout: Layout) {
+    future::record_dealloc(layout.size());
+    region::record_dealloc(layout.size());
+    watermark::record_dealloc(layout.size());
+    timeline::record_dealloc();
+    #[cfg(all(feature = "alloc-shm", target_os = "linux"))]
+    shm::record_dealloc();
+    #[cfg(feature = "alloc-spans")]
+    spans::record_dealloc(layout.size());
+}
+
+#[inline]
+pub(crate) fn record_realloc(old_layout: Layout, new_layout: Layout) {
+    record_alloc(new_layout);
+    record_dealloc(old_layout);
+}
+
+
+//
+// Observers (no_alloc sections, chrome trace). These log, lock and
+// allocate, so wrappers only call them for the caller's blocks.
+//
+#[inline]
+pub(crate) fn observe_alloc(layout: Layout) {
+    no_alloc::check_alloc(layout.size());
+    chrome::record_alloc(layout.size());
+}
+
+#[inline]
+pub(crate) fn observe_dealloc(layout: Layout) { chrome::record_dealloc(layout.size()); }
+
+#[inline]
+pub(crate) fn observe_realloc(old_layout: Layout, new_layout: Layout) {
+    observe_alloc(new_layout);
+    observe_dealloc(old_layout);
+}
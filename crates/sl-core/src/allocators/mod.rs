@@ -8,17 +8,101 @@
  * Purpose:
  *   Set of custom allocators tailored to specific scenerios.
  *
+ *   Without the `std` feature only `Counting` (over a user supplied inner
+ *   allocator) and the counters are available.
+ *
  */
 
+cfg_alloc_any! {
+    mod counter;
+    pub use counter::{AtomicCounter, Counter};
+
+    // Everything driven by the allocator hooks needs thread locals.
+    cfg_std! {
+        pub use counter::ShardedCounter;
+
+        mod hooks;
+
+        mod future;
+        pub use future::{AllocCounts, CountAllocs, CountAllocsExt};
+
+        mod no_alloc;
+        pub use no_alloc::{set_no_alloc_action, NoAllocAction, NoAllocGuard};
+
+        pub mod chrome;
+
+        mod region;
+        pub use region::{dump_regions, region_stats, Region, RegionGuard, RegionStats};
+
+        mod autodump;
+        pub use autodump::{dump_now, install_dump_hooks, DumpConfig, MemoryReport};
+
+        pub mod timeline;
+
+        mod watermark;
+        pub use watermark::{
+            live_bytes, on_watermark, remove_watermark, set_watermark_interval, Crossing, WatermarkId,
+        };
+    }
+}
+
+cfg_alloc_shm! {
+    pub mod shm;
+}
+
+cfg_alloc_spans! {
+    mod spans;
+    pub use spans::AllocLayer;
+}
+
 cfg_alloc_count! {
     mod counting;
     pub use counting::Counting;
 }
 
+cfg_alloc_dump! {
+    pub mod dump;
+    pub mod filter;
+    pub mod replay;
+}
+
+cfg_alloc_pages! {
+    mod pages;
+    pub use pages::PageAlloc;
+}
+
 cfg_alloc_trace! {
     mod tracing;
     pub use tracing::Tracing;
 
+    mod breakpoint;
+    pub use breakpoint::{break_on_alloc, clear_alloc_break, AllocBreak};
+
+    mod watch;
+    pub use watch::{unwatch, watch_address, watch_range, WatchId};
+
     mod tracker;
-    pub use tracker::{Tracker, DefaultTracker};
+    pub use tracker::{Tracker, DefaultTracker, DumpMode};
+
+    mod tee;
+    pub use tee::{DynTracker, Tee};
+
+    mod stacks;
+    pub use stacks::StackCapture;
+
+    mod recorder;
+    pub use recorder::Recorder;
+
+    mod flight;
+    pub use flight::FlightRecorder;
+
+    mod slack;
+    pub use slack::{Slack, SlackTracker};
+    #[cfg(target_os = "linux")]
+    pub use slack::malloc_usable_size;
+}
+
+cfg_alloc_api! {
+    mod api;
+    pub use allocator_api2;
 }
@@ -0,0 +1,115 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/no_alloc
+ *
+ * Purpose:
+ *    Enforces allocation-free sections for latency critical code. While a
+ *    `NoAllocGuard` is alive on a thread, any allocation made on that thread
+ *    through the sl_core allocators is reported with a backtrace.
+ *
+ *    Allocators are not allowed to unwind, so `NoAllocAction::Panic` logs
+ *    the offending allocation where it happens and then panics once the
+ *    section is exited. Use `NoAllocAction::Abort` to stop right at the
+ *    allocation instead (e.g. under a debugger).
+ *
+ */
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::atomic::{
+        AtomicU8,
+        Ordering,
+    },
+};
+
+
+thread_entry_guard!(NO_ALLOC_GUARD);
+
+thread_local! {
+    static VIOLATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+static ACTION: AtomicU8 = AtomicU8::new(NoAllocAction::Panic as u8);
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NoAllocAction {
+    // Log the allocation (with backtrace) and carry on
+    Log   = 0,
+    // Log the allocation and panic when the section exits
+    Panic = 1,
+    // Log the allocation and abort the process immediately
+    Abort = 2,
+}
+
+impl NoAllocAction {
+    fn current() -> Self {
+        match ACTION.load(Ordering::Relaxed) {
+            | 0 => Self::Log,
+            | 2 => Self::Abort,
+            | _ => Self::Panic,
+        }
+    }
+}
+
+/// Sets what happens when an allocation is made inside a no_alloc section.
+pub fn set_no_alloc_action(action: NoAllocAction) { ACTION.store(action as u8, Ordering::Relaxed); }
+
+
+//
+// Forbids allocations on the current thread until dropped
+//
+pub struct NoAllocGuard {
+    prev:       bool,
+    violations: usize,
+    _unsend:    PhantomData<*const ()>,
+}
+
+impl NoAllocGuard {
+    pub fn enter() -> Self {
+        Self {
+            prev:       NO_ALLOC_GUARD.with(|g| g.replace(true)),
+            violations: VIOLATIONS.with(|v| v.get()),
+            _unsend:    PhantomData,
+        }
+    }
+}
+
+impl Drop for NoAllocGuard {
+    fn drop(&mut self) {
+        NO_ALLOC_GUARD.with(|g| g.set(self.prev));
+
+        let count = VIOLATIONS.with(|v| v.get()) - self.violations;
+        if count > 0 && NoAllocAction::current() == NoAllocAction::Panic && !std::thread::panicking() {
+            panic!("{} allocation(s) made inside a no_alloc section", count);
+        }
+    }
+}
+
+
+#[inline]
+pub(crate) fn check_alloc(size: usize) {
+    // Lift the restriction while reporting; logging and capturing the
+    // backtrace both allocate.
+    if !NO_ALLOC_GUARD.try_with(|g| g.replace(false)).unwrap_or(false) {
+        return;
+    }
+
+    VIOLATIONS.with(|v| v.set(v.get() + 1));
+    log::error!(
+        "allocation of {} bytes inside a no_alloc section\n{}",
+        size,
+        std::backtrace::Backtrace::force_capture()
+    );
+
+    if NoAllocAction::current() == NoAllocAction::Abort {
+        std::process::abort();
+    }
+
+    NO_ALLOC_GUARD.with(|g| g.set(true));
+}
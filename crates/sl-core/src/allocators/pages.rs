@@ -0,0 +1,191 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/pages
+ *
+ * Purpose:
+ *    Page level allocator for Linux. Large requests are mapped directly
+ *    with `mmap` and handed back to the OS with `munmap` the moment they
+ *    are freed; everything else goes to the inner allocator. Meant to be
+ *    used as the `A` parameter of `Counting` / `Tracing`.
+ *
+ *    Requests aligned above the page size always go to the inner
+ *    allocator. Large blocks are resized with `mremap`, so growing a big
+ *    buffer never copies it.
+ *
+ */
+
+use std::{
+    alloc::{
+        GlobalAlloc,
+        Layout,
+        System,
+    },
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+};
+
+
+pub const DEFAULT_THRESHOLD: usize = 256 * 1024;
+
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+
+pub struct PageAlloc<A = System>
+where
+    A: GlobalAlloc,
+{
+    inner:      A,
+    threshold:  usize,
+    huge_pages: bool,
+    mapped:     AtomicUsize,
+}
+
+impl PageAlloc<System> {
+    pub const fn default() -> Self { Self::new(System, DEFAULT_THRESHOLD, false) }
+}
+
+impl<A> PageAlloc<A>
+where
+    A: GlobalAlloc,
+{
+    /// Requests of at least `threshold` bytes are mapped directly. With
+    /// `huge_pages` the kernel is asked to back mappings with transparent
+    /// huge pages (`MADV_HUGEPAGE`).
+    pub const fn new(inner: A, threshold: usize, huge_pages: bool) -> Self {
+        Self {
+            inner,
+            threshold,
+            huge_pages,
+            mapped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes currently mapped for large allocations.
+    pub fn mapped(&self) -> usize { self.mapped.load(Ordering::Relaxed) }
+
+    #[inline]
+    fn is_large(&self, layout: Layout) -> bool {
+        layout.size() >= self.threshold && layout.align() <= page_size()
+    }
+
+    unsafe fn map(&self, size: usize) -> *mut u8 {
+        let len = round_to_pages(size);
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+
+        if ptr == libc::MAP_FAILED {
+            return std::ptr::null_mut();
+        }
+
+        self.advise(ptr, len);
+        self.mapped.fetch_add(len, Ordering::Relaxed);
+        ptr.cast()
+    }
+
+    unsafe fn unmap(&self, ptr: *mut u8, size: usize) {
+        let len = round_to_pages(size);
+        libc::munmap(ptr.cast(), len);
+        self.mapped.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    unsafe fn remap(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+        let (old_len, new_len) = (round_to_pages(old_size), round_to_pages(new_size));
+        if old_len == new_len {
+            return ptr;
+        }
+
+        let new_ptr = libc::mremap(ptr.cast(), old_len, new_len, libc::MREMAP_MAYMOVE);
+        if new_ptr == libc::MAP_FAILED {
+            return std::ptr::null_mut();
+        }
+
+        self.advise(new_ptr, new_len);
+        self.mapped.fetch_add(new_len, Ordering::Relaxed);
+        self.mapped.fetch_sub(old_len, Ordering::Relaxed);
+        new_ptr.cast()
+    }
+
+    // Only a hint; mappings work the same whether or not it is honoured.
+    unsafe fn advise(&self, ptr: *mut libc::c_void, len: usize) {
+        if self.huge_pages {
+            libc::madvise(ptr, len, libc::MADV_HUGEPAGE);
+        }
+    }
+}
+
+unsafe impl<A> GlobalAlloc for PageAlloc<A>
+where
+    A: GlobalAlloc,
+{
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.is_large(layout) {
+            | true => self.map(layout.size()),
+            | false => self.inner.alloc(layout),
+        }
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // Fresh anonymous mappings are already zeroed.
+        match self.is_large(layout) {
+            | true => self.map(layout.size()),
+            | false => self.inner.alloc_zeroed(layout),
+        }
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match self.is_large(layout) {
+            | true => self.unmap(ptr, layout.size()),
+            | false => self.inner.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // SAFETY: the caller guarantees `new_size` is valid for the
+        // original alignment.
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match (self.is_large(layout), self.is_large(new_layout)) {
+            | (true, true) => self.remap(ptr, layout.size(), new_size),
+            | (false, false) => self.inner.realloc(ptr, layout, new_size),
+            | _ => {
+                // Crossing the threshold moves the block between the two.
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            },
+        }
+    }
+}
+
+
+fn page_size() -> usize {
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        | 0 => {
+            // SAFETY: sysconf has no preconditions.
+            let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize;
+            PAGE_SIZE.store(size, Ordering::Relaxed);
+            size
+        },
+        | size => size,
+    }
+}
+
+#[inline]
+fn round_to_pages(size: usize) -> usize { size.max(1).next_multiple_of(page_size()) }
@@ -0,0 +1,154 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/recorder
+ *
+ * Purpose:
+ *    Tracker that records a compact trace of every allocation operation
+ *    (size, alignment, thread and order) for later replay against other
+ *    allocators. No backtraces are captured, so it is cheap enough to leave
+ *    on for a full production run.
+ *
+ */
+
+use std::{
+    alloc::Layout,
+    cell::Cell,
+    collections::BTreeMap,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+};
+
+use super::{
+    filter::FilterPolicy,
+    replay::{
+        self,
+        TraceEvent,
+        TraceOp,
+    },
+    Tracker,
+};
+
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_NO: Cell<usize> = const { Cell::new(usize::MAX) };
+}
+
+
+pub struct Recorder {
+    trace:   Vec<u8>,
+    live:    BTreeMap<usize, usize>,
+    next_id: usize,
+}
+
+impl Recorder {
+    pub const fn new() -> Self {
+        Self {
+            trace:   Vec::new(),
+            live:    BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn record(&mut self, id: usize, op: TraceOp) {
+        if self.trace.is_empty() {
+            replay::write_trace_header(&mut self.trace);
+        }
+
+        let e = TraceEvent {
+            thread: thread_no(),
+            id,
+            op,
+        };
+        replay::encode_event(&mut self.trace, &e);
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self { Self::new() }
+}
+
+impl Tracker for Recorder {
+    fn dump_info(
+        &mut self,
+        out: &mut dyn std::io::Write,
+        _filter: &FilterPolicy,
+    ) -> std::io::Result<()> {
+        if self.trace.is_empty() {
+            replay::write_trace_header(&mut self.trace);
+        }
+
+        out.write_all(&self.trace)
+    }
+
+    // Replays need dense block ids of their own, so the allocator's
+    // sequence numbers aren't used.
+    fn track_alloc(&mut self, _id: usize, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.live.insert(ptr as usize, id);
+
+        self.record(
+            id,
+            TraceOp::Alloc {
+                size:  layout.size(),
+                align: layout.align(),
+            },
+        );
+    }
+
+    fn track_dealloc(&mut self, ptr: *mut u8, _layout: Layout) {
+        // Blocks allocated before recording started are skipped.
+        if let Some(id) = self.live.remove(&(ptr as usize)) {
+            self.record(id, TraceOp::Dealloc);
+        }
+    }
+
+    fn track_realloc(
+        &mut self,
+        id: usize,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        _old_layout: Layout,
+        new_layout: Layout,
+    ) {
+        // The block keeps its id wherever it ends up. A block from before
+        // recording started shows up as a fresh allocation.
+        match self.live.remove(&(old_ptr as usize)) {
+            | Some(id) => {
+                self.live.insert(new_ptr as usize, id);
+                self.record(
+                    id,
+                    TraceOp::Realloc {
+                        size: new_layout.size(),
+                    },
+                );
+            },
+            | None => self.track_alloc(id, new_ptr, new_layout),
+        }
+    }
+}
+
+
+// Small, dense per-thread numbers in order of each thread's first
+// allocation.
+pub(super) fn thread_no() -> usize {
+    THREAD_NO
+        .try_with(|n| {
+            if n.get() == usize::MAX {
+                n.set(NEXT_THREAD.fetch_add(1, Ordering::Relaxed));
+            }
+            n.get()
+        })
+        .unwrap_or(0)
+}
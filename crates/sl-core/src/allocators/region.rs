@@ -0,0 +1,276 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/region
+ *
+ * Purpose:
+ *    Named allocation regions. Each thread keeps track of the innermost
+ *    region it is executing in and the allocators attribute every
+ *    allocation / de-allocation to it. Gives a per-subsystem breakdown
+ *    without the cost of capturing backtraces.
+ *
+ *    Regions are registered on first entry and live for the life of the
+ *    process. Entering a region with the same name from different call
+ *    sites shares the same counters.
+ *
+ *    A free is charged to the region the freeing thread is in, not to the
+ *    one that made the allocation (the allocators keep no per-block
+ *    state). Blocks handed across regions skew both sides, so the "net"
+ *    counts here are churn per region rather than a leak measure; the
+ *    tracing allocator's leak report records the owning region at
+ *    allocation time and breaks leaks down by it instead.
+ *
+ */
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
+use super::chrome;
+
+
+const MAX_REGIONS: usize = 64;
+const NO_REGION: usize = 0;
+const UNTRACKED: usize = usize::MAX;
+
+static NAMES: Mutex<[&str; MAX_REGIONS]> = Mutex::new([""; MAX_REGIONS]);
+static COUNTERS: [Counters; MAX_REGIONS] = [const { Counters::new() }; MAX_REGIONS];
+
+thread_local! {
+    static CURRENT: Cell<usize> = const { Cell::new(NO_REGION) };
+}
+
+
+//
+// Per-region counters (shared by all threads)
+//
+struct Counters {
+    allocs:      AtomicUsize,
+    frees:       AtomicUsize,
+    bytes_alloc: AtomicUsize,
+    bytes_freed: AtomicUsize,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            allocs:      AtomicUsize::new(0),
+            frees:       AtomicUsize::new(0),
+            bytes_alloc: AtomicUsize::new(0),
+            bytes_freed: AtomicUsize::new(0),
+        }
+    }
+}
+
+
+//
+// Snapshot of a single region's counters
+//
+#[derive(Clone, Copy, Debug)]
+pub struct RegionStats {
+    pub name:        &'static str,
+    pub allocs:      usize,
+    pub frees:       usize,
+    pub bytes_alloc: usize,
+    pub bytes_freed: usize,
+}
+
+impl RegionStats {
+    // Allocations minus frees made inside the region; negative when the
+    // region frees blocks allocated elsewhere. Not a leak count.
+    pub fn net(&self) -> isize { self.allocs as isize - self.frees as isize }
+
+    pub fn net_bytes(&self) -> isize { self.bytes_alloc as isize - self.bytes_freed as isize }
+}
+
+
+//
+// A named region. Normally declared as a static by `alloc_region!`.
+//
+pub struct Region {
+    name: &'static str,
+    id:   AtomicUsize,
+}
+
+impl Region {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            id: AtomicUsize::new(NO_REGION),
+        }
+    }
+
+    /// Makes this the innermost region on the current thread until the
+    /// returned guard is dropped. The guard must not be held across an
+    /// `.await`.
+    pub fn enter(&self) -> RegionGuard {
+        let mut id = self.id.load(Ordering::Acquire);
+        if id == NO_REGION {
+            id = register(self.name).unwrap_or(UNTRACKED);
+            self.id.store(id, Ordering::Release);
+        }
+
+        // Regions that didn't fit in the table keep reporting to whatever
+        // region encloses them.
+        if id == UNTRACKED {
+            id = current();
+        }
+
+        chrome::enter_region(self.name);
+        RegionGuard {
+            name:    self.name,
+            prev:    CURRENT.with(|c| c.replace(id)),
+            _unsend: PhantomData,
+        }
+    }
+}
+
+
+//
+// Restores the enclosing region when dropped
+//
+pub struct RegionGuard {
+    name:    &'static str,
+    prev:    usize,
+    _unsend: PhantomData<*const ()>,
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|c| c.set(self.prev));
+        chrome::exit_region(self.name);
+    }
+}
+
+
+/// Current counters for every region that has been entered at least once.
+pub fn region_stats() -> Vec<RegionStats> {
+    let names = *NAMES.lock().expect("unable to unwrap region names");
+
+    names
+        .iter()
+        .enumerate()
+        .skip(1)
+        .take_while(|(_, name)| !name.is_empty())
+        .map(|(id, name)| {
+            let c = &COUNTERS[id];
+            RegionStats {
+                name,
+                allocs: c.allocs.load(Ordering::Relaxed),
+                frees: c.frees.load(Ordering::Relaxed),
+                bytes_alloc: c.bytes_alloc.load(Ordering::Relaxed),
+                bytes_freed: c.bytes_freed.load(Ordering::Relaxed),
+            }
+        })
+        .collect()
+}
+
+/// Writes a per-region breakdown. Writes nothing if no region was entered.
+pub fn dump_regions<Writer: std::io::Write + ?Sized>(out: &mut Writer) -> std::io::Result<()> {
+    let stats = region_stats();
+    if stats.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out, "\n\n=============== REGIONS ===============")?;
+    for s in stats.iter() {
+        writeln!(
+            out,
+            "<{}> Allocations (Total {}, Freed {}, Net {}), Bytes (Total {}, Freed {}, Net {})",
+            s.name,
+            s.allocs,
+            s.frees,
+            s.net(),
+            s.bytes_alloc,
+            s.bytes_freed,
+            s.net_bytes(),
+        )?;
+    }
+
+    Ok(())
+}
+
+
+/// Identifier of the innermost region on the current thread (0 if none).
+#[inline]
+pub(crate) fn current() -> usize { CURRENT.try_with(|c| c.get()).unwrap_or(NO_REGION) }
+
+cfg_alloc_trace! {
+    /// Name registered for a region identifier.
+    pub(crate) fn name_of(id: usize) -> &'static str {
+        match id {
+            | NO_REGION => "none",
+            | id => NAMES.lock().expect("unable to unwrap region names")[id],
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn record_alloc(size: usize) {
+    let id = current();
+    if id != NO_REGION {
+        COUNTERS[id].allocs.fetch_add(1, Ordering::Relaxed);
+        COUNTERS[id].bytes_alloc.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+#[inline]
+pub(crate) fn record_dealloc(size: usize) {
+    let id = current();
+    if id != NO_REGION {
+        COUNTERS[id].frees.fetch_add(1, Ordering::Relaxed);
+        COUNTERS[id].bytes_freed.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+
+fn register(name: &'static str) -> Option<usize> {
+    // An empty name marks a free slot.
+    if name.is_empty() {
+        log::warn!("allocation regions need a name, an unnamed region will not be tracked");
+        return None;
+    }
+
+    let mut names = NAMES.lock().expect("unable to unwrap region names");
+
+    // Slot 0 is reserved for "no region".
+    for (id, n) in names.iter_mut().enumerate().skip(1) {
+        if n.is_empty() {
+            *n = name;
+            return Some(id);
+        }
+
+        if *n == name {
+            return Some(id);
+        }
+    }
+
+    log::warn!("too many allocation regions, <{}> will not be tracked", name);
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn unnamed_regions_are_not_registered() {
+        assert_eq!(register(""), None);
+
+        let id = register("region tests").unwrap();
+        assert_eq!(register("region tests"), Some(id));
+        assert!(region_stats().iter().any(|s| s.name == "region tests"));
+    }
+}
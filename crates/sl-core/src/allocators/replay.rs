@@ -0,0 +1,506 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/replay
+ *
+ * Purpose:
+ *    Compact allocation traces (as captured by `Recorder`) and a driver
+ *    that replays them against any `GlobalAlloc`. Used to compare
+ *    allocators on real allocation patterns rather than synthetic loops.
+ *
+ *    Trace format: a magic header followed by one record per operation in
+ *    the order they happened. All numbers are LEB128 encoded.
+ *      alloc:   0 <thread> <id> <size> <align>
+ *      dealloc: 1 <thread> <id>
+ *      realloc: 2 <thread> <id> <new size>
+ *
+ *    Every allocation gets its own id (0, 1, 2, ... in alloc record order);
+ *    a realloc keeps the id of the block it resizes.
+ *
+ */
+
+use std::{
+    alloc::{
+        GlobalAlloc,
+        Layout,
+    },
+    io::{
+        Error,
+        ErrorKind,
+    },
+    sync::atomic::{
+        AtomicPtr,
+        AtomicUsize,
+        Ordering,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+
+const TRACE_MAGIC: &[u8; 8] = b"SLATRC01";
+
+const OP_ALLOC: u8 = 0;
+const OP_DEALLOC: u8 = 1;
+const OP_REALLOC: u8 = 2;
+
+// `replay_threaded` spawns a thread per recorded thread; a trace claiming
+// more than this is corrupt.
+const MAX_THREADS: usize = 4096;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    Alloc { size: usize, align: usize },
+    Dealloc,
+    Realloc { size: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEvent {
+    pub thread: usize,
+    pub id:     usize,
+    pub op:     TraceOp,
+}
+
+
+//
+// Decoded allocation trace
+//
+#[derive(Clone, Debug, Default)]
+pub struct AllocTrace {
+    pub events: Vec<TraceEvent>,
+}
+
+impl AllocTrace {
+    pub fn read(data: &[u8]) -> std::io::Result<Self> {
+        let mut data = data
+            .strip_prefix(&TRACE_MAGIC[..])
+            .ok_or_else(|| invalid("missing allocation trace header"))?;
+
+        let mut trace = AllocTrace::default();
+        while let Some((&op, rest)) = data.split_first() {
+            data = rest;
+
+            let thread = read_leb(&mut data)?;
+            if thread >= MAX_THREADS {
+                return Err(invalid("thread index out of range"));
+            }
+
+            let id = read_leb(&mut data)?;
+            let op = match op {
+                | OP_ALLOC => TraceOp::Alloc {
+                    size:  read_leb(&mut data)?,
+                    align: read_leb(&mut data)?,
+                },
+                | OP_DEALLOC => TraceOp::Dealloc,
+                | OP_REALLOC => TraceOp::Realloc {
+                    size: read_leb(&mut data)?,
+                },
+                | _ => return Err(invalid("unknown trace op")),
+            };
+
+            trace.events.push(TraceEvent { thread, id, op });
+        }
+
+        // Ids are dense (one per alloc record) and size the replay's block
+        // table, so anything past them is a corrupt trace.
+        let allocs = trace
+            .events
+            .iter()
+            .filter(|e| matches!(e.op, TraceOp::Alloc { .. }))
+            .count();
+        if trace.events.iter().any(|e| e.id >= allocs) {
+            return Err(invalid("block id out of range"));
+        }
+
+        // Every size has to make a valid layout with its block's alignment;
+        // replay hands them straight to the allocator.
+        let mut aligns = vec![1usize; allocs];
+        for e in trace.events.iter() {
+            let (size, align) = match e.op {
+                | TraceOp::Alloc { size, align } => {
+                    aligns[e.id] = align;
+                    (size, align)
+                },
+                | TraceOp::Realloc { size } => (size, aligns[e.id]),
+                | TraceOp::Dealloc => continue,
+            };
+            if Layout::from_size_align(size.max(1), align).is_err() {
+                return Err(invalid("bad size or alignment"));
+            }
+        }
+
+        Ok(trace)
+    }
+
+    pub fn write<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        write_trace_header(&mut buf);
+        for e in self.events.iter() {
+            encode_event(&mut buf, e);
+        }
+        out.write_all(&buf)
+    }
+
+    pub fn threads(&self) -> usize { self.events.iter().map(|e| e.thread + 1).max().unwrap_or(0) }
+
+    /// Highest number of live bytes at any point, in recorded order.
+    pub fn peak_bytes(&self) -> usize {
+        let mut sizes = vec![0usize; self.slots()];
+        let (mut live, mut peak) = (0usize, 0usize);
+
+        for e in self.events.iter() {
+            match e.op {
+                | TraceOp::Alloc { size, .. } => {
+                    sizes[e.id] = size;
+                    live += size;
+                },
+                | TraceOp::Dealloc => live -= std::mem::take(&mut sizes[e.id]),
+                | TraceOp::Realloc { size } => {
+                    live = live - sizes[e.id] + size;
+                    sizes[e.id] = size;
+                },
+            }
+            peak = peak.max(live);
+        }
+
+        peak
+    }
+
+    fn slots(&self) -> usize { self.events.iter().map(|e| e.id + 1).max().unwrap_or(0) }
+}
+
+
+pub fn write_trace_header(buf: &mut Vec<u8>) { buf.extend_from_slice(TRACE_MAGIC); }
+
+pub fn encode_event(buf: &mut Vec<u8>, e: &TraceEvent) {
+    match e.op {
+        | TraceOp::Alloc { size, align } => {
+            buf.push(OP_ALLOC);
+            write_leb(buf, e.thread);
+            write_leb(buf, e.id);
+            write_leb(buf, size);
+            write_leb(buf, align);
+        },
+        | TraceOp::Dealloc => {
+            buf.push(OP_DEALLOC);
+            write_leb(buf, e.thread);
+            write_leb(buf, e.id);
+        },
+        | TraceOp::Realloc { size } => {
+            buf.push(OP_REALLOC);
+            write_leb(buf, e.thread);
+            write_leb(buf, e.id);
+            write_leb(buf, size);
+        },
+    }
+}
+
+
+//
+// Replay results
+//
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayStats {
+    pub ops:      usize,
+    pub failures: usize,
+    pub elapsed:  Duration,
+}
+
+
+/// Replays the trace on the calling thread in recorded order.
+pub fn replay<A: GlobalAlloc>(trace: &AllocTrace, alloc: &A) -> ReplayStats {
+    let mut slots: Vec<(*mut u8, Layout)> = vec![(std::ptr::null_mut(), Layout::new::<u8>()); trace.slots()];
+    let mut failures = 0;
+
+    let start = Instant::now();
+    for e in trace.events.iter() {
+        // SAFETY: every pointer handed back to the allocator came from it
+        // with the layout stored alongside it.
+        unsafe {
+            if !apply(alloc, &mut slots[e.id], e.op) {
+                failures += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    release(alloc, slots.into_iter());
+
+    ReplayStats {
+        ops: trace.events.len(),
+        failures,
+        elapsed,
+    }
+}
+
+/// Replays the trace with one thread per recorded thread. Each thread runs
+/// its own operations in order; an operation on a block another thread
+/// owns waits until every earlier operation on that block has happened.
+pub fn replay_threaded<A: GlobalAlloc + Sync>(trace: &AllocTrace, alloc: &A) -> ReplayStats {
+    let slots: Vec<Slot> = (0..trace.slots()).map(|_| Slot::new()).collect();
+
+    // Position of each event in its block's history.
+    let mut seen = vec![0usize; slots.len()];
+    let mut per_thread: Vec<Vec<(usize, &TraceEvent)>> = vec![Vec::new(); trace.threads()];
+    for e in trace.events.iter() {
+        per_thread[e.thread].push((seen[e.id], e));
+        seen[e.id] += 1;
+    }
+
+    let failures = AtomicUsize::new(0);
+
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for events in per_thread.iter() {
+            let (slots, failures) = (&slots, &failures);
+            s.spawn(move || {
+                for (seq, e) in events.iter() {
+                    let slot = &slots[e.id];
+                    while slot.seq.load(Ordering::Acquire) != *seq {
+                        std::hint::spin_loop();
+                    }
+
+                    let mut state = (slot.ptr.load(Ordering::Relaxed), slot.layout(e.op));
+                    // SAFETY: see `replay`.
+                    if !unsafe { apply(alloc, &mut state, e.op) } {
+                        failures.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    slot.ptr.store(state.0, Ordering::Relaxed);
+                    slot.align.store(state.1.align(), Ordering::Relaxed);
+                    slot.size.store(state.1.size(), Ordering::Relaxed);
+                    slot.seq.store(seq + 1, Ordering::Release);
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    release(
+        alloc,
+        slots.iter().map(|s| {
+            (
+                s.ptr.load(Ordering::Relaxed),
+                s.layout(TraceOp::Dealloc),
+            )
+        }),
+    );
+
+    ReplayStats {
+        ops: trace.events.len(),
+        failures: failures.into_inner(),
+        elapsed,
+    }
+}
+
+
+struct Slot {
+    ptr:   AtomicPtr<u8>,
+    size:  AtomicUsize,
+    align: AtomicUsize,
+    seq:   AtomicUsize,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            ptr:   AtomicPtr::new(std::ptr::null_mut()),
+            size:  AtomicUsize::new(0),
+            align: AtomicUsize::new(1),
+            seq:   AtomicUsize::new(0),
+        }
+    }
+
+    fn layout(&self, op: TraceOp) -> Layout {
+        match op {
+            | TraceOp::Alloc { size, align } => layout(size, align),
+            | _ => layout(self.size.load(Ordering::Relaxed), self.align.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+
+/// Applies a single operation to a block. Returns false if the allocator
+/// failed to satisfy it.
+unsafe fn apply<A: GlobalAlloc>(alloc: &A, slot: &mut (*mut u8, Layout), op: TraceOp) -> bool {
+    match op {
+        | TraceOp::Alloc { size, align } => {
+            slot.1 = layout(size, align);
+            slot.0 = alloc.alloc(slot.1);
+            !slot.0.is_null()
+        },
+        | TraceOp::Dealloc => {
+            if !slot.0.is_null() {
+                alloc.dealloc(slot.0, slot.1);
+                slot.0 = std::ptr::null_mut();
+            }
+            true
+        },
+        | TraceOp::Realloc { size } => {
+            if slot.0.is_null() {
+                return false;
+            }
+
+            let ptr = alloc.realloc(slot.0, slot.1, size.max(1));
+            if ptr.is_null() {
+                return false;
+            }
+
+            slot.0 = ptr;
+            slot.1 = layout(size, slot.1.align());
+            true
+        },
+    }
+}
+
+fn release<A: GlobalAlloc>(alloc: &A, slots: impl Iterator<Item = (*mut u8, Layout)>) {
+    for (ptr, layout) in slots {
+        if !ptr.is_null() {
+            // SAFETY: see `replay`.
+            unsafe { alloc.dealloc(ptr, layout) };
+        }
+    }
+}
+
+// Zero-sized requests are bumped to a single byte; `GlobalAlloc` doesn't
+// allow zero-sized layouts. `AllocTrace::read` rejects anything else that
+// doesn't make a layout.
+fn layout(size: usize, align: usize) -> Layout {
+    Layout::from_size_align(size.max(1), align).expect("trace has an invalid layout")
+}
+
+fn write_leb(buf: &mut Vec<u8>, mut v: usize) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_leb(data: &mut &[u8]) -> std::io::Result<usize> {
+    let mut v = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or_else(|| invalid("allocation trace is truncated"))?;
+        *data = rest;
+
+        v |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+
+    Err(invalid("bad LEB128 value"))
+}
+
+fn invalid(msg: &str) -> Error { Error::new(ErrorKind::InvalidData, msg.to_string()) }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn event(thread: usize, id: usize, op: TraceOp) -> TraceEvent { TraceEvent { thread, id, op } }
+
+    #[test]
+    fn leb_round_trip() {
+        for v in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as usize, usize::MAX] {
+            let mut buf = Vec::new();
+            write_leb(&mut buf, v);
+
+            let mut data = &buf[..];
+            assert_eq!(read_leb(&mut data).unwrap(), v);
+            assert!(data.is_empty());
+        }
+    }
+
+    #[test]
+    fn leb_rejects_bad_input() {
+        let mut buf = Vec::new();
+        write_leb(&mut buf, 0x4000);
+        let mut truncated = &buf[..buf.len() - 1];
+        assert!(read_leb(&mut truncated).is_err());
+
+        // Continuation bits past the width of a usize.
+        let overlong = [0xffu8; 11];
+        assert!(read_leb(&mut &overlong[..]).is_err());
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        let trace = AllocTrace {
+            events: vec![
+                event(0, 0, TraceOp::Alloc { size: 24, align: 8 }),
+                event(1, 1, TraceOp::Alloc { size: 300, align: 16 }),
+                event(0, 0, TraceOp::Realloc { size: 1000 }),
+                event(1, 0, TraceOp::Dealloc),
+                event(1, 1, TraceOp::Dealloc),
+            ],
+        };
+
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let read = AllocTrace::read(&out).unwrap();
+
+        let events: Vec<_> = read.events.iter().map(|e| (e.thread, e.id, e.op)).collect();
+        let expected: Vec<_> = trace.events.iter().map(|e| (e.thread, e.id, e.op)).collect();
+        assert_eq!(events, expected);
+        assert_eq!(read.threads(), 2);
+        assert_eq!(read.peak_bytes(), 1300);
+    }
+
+    #[test]
+    fn trace_rejects_bad_input() {
+        assert!(AllocTrace::read(b"SLATRC00").is_err());
+
+        let mut buf = Vec::new();
+        write_trace_header(&mut buf);
+        encode_event(&mut buf, &event(0, 0, TraceOp::Alloc { size: 8, align: 8 }));
+        assert!(AllocTrace::read(&buf[..buf.len() - 1]).is_err());
+
+        encode_event(&mut buf, &event(0, 1, TraceOp::Dealloc));
+        assert!(AllocTrace::read(&buf).is_err());
+    }
+
+    #[test]
+    fn trace_rejects_corrupt_records() {
+        let read = |events: &[TraceEvent]| {
+            let mut buf = Vec::new();
+            write_trace_header(&mut buf);
+            for e in events {
+                encode_event(&mut buf, e);
+            }
+            AllocTrace::read(&buf)
+        };
+        let alloc = TraceOp::Alloc { size: 8, align: 8 };
+
+        assert!(read(&[event(MAX_THREADS - 1, 0, alloc)]).is_ok());
+        assert!(read(&[event(MAX_THREADS, 0, alloc)]).is_err());
+        assert!(read(&[event(usize::MAX, 0, alloc)]).is_err());
+
+        // Alignment has to be a power of two.
+        assert!(read(&[event(0, 0, TraceOp::Alloc { size: 8, align: 0 })]).is_err());
+        assert!(read(&[event(0, 0, TraceOp::Alloc { size: 8, align: 3 })]).is_err());
+
+        // Sizes can't overflow isize once rounded up to the alignment.
+        assert!(read(&[event(0, 0, TraceOp::Alloc { size: usize::MAX, align: 8 })]).is_err());
+        assert!(read(&[event(0, 0, alloc), event(0, 0, TraceOp::Realloc { size: usize::MAX })]).is_err());
+        assert!(read(&[
+            event(0, 0, TraceOp::Alloc { size: 0, align: 1 }),
+            event(0, 0, TraceOp::Realloc { size: 0 }),
+        ])
+        .is_ok());
+    }
+}
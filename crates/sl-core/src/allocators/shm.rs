@@ -0,0 +1,424 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/shm
+ *
+ * Purpose:
+ *    Live allocator stats in POSIX shared memory. A process that calls
+ *    `publish` gets a small segment named `/sl-alloc.<pid>` (visible under
+ *    /dev/shm) that a helper thread refreshes with the allocation / free
+ *    totals and the live / peak byte counts. Any other process can attach
+ *    to it by PID (see `StatsView`, and the `sl-memtop` viewer) without
+ *    the publisher doing anything else.
+ *
+ *    Writes are wrapped in a sequence lock so readers never see a half
+ *    updated sample (a reader that keeps racing writes, or finds a write
+ *    that never finished, gets an error instead). The peak is the highest
+ *    live byte count seen by the helper thread, so spikes shorter than the
+ *    interval are missed.
+ *
+ */
+
+use std::{
+    ffi::CString,
+    io,
+    sync::atomic::{
+        fence,
+        AtomicU64,
+        Ordering,
+    },
+};
+
+
+const MAGIC: u64 = u64::from_le_bytes(*b"sl-alloc");
+const VERSION: u32 = 1;
+const READ_ATTEMPTS: usize = 1000;
+
+
+/// One sample of a process's allocator stats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub allocs:     u64,
+    pub frees:      u64,
+    pub live_bytes: u64,
+    pub peak_bytes: u64,
+    // Publisher's wall clock (ms since the Unix epoch) at the last refresh
+    pub updated_ms: u64,
+}
+
+impl Stats {
+    pub fn live_blocks(&self) -> u64 { self.allocs.saturating_sub(self.frees) }
+}
+
+
+//
+// Shared memory layout (all fields naturally aligned; readers check the
+// magic and version before trusting anything else)
+//
+#[repr(C)]
+struct Segment {
+    magic:       AtomicU64,
+    version:     u32,
+    pid:         u32,
+    interval_ms: AtomicU64,
+    seq:         AtomicU64,
+    allocs:      AtomicU64,
+    frees:       AtomicU64,
+    live_bytes:  AtomicU64,
+    peak_bytes:  AtomicU64,
+    updated_ms:  AtomicU64,
+}
+
+impl Segment {
+    // None if every attempt raced a write. A publisher killed mid-write
+    // leaves the sequence odd for good, so readers can't wait it out.
+    fn read(&self) -> Option<Stats> {
+        for _ in 0..READ_ATTEMPTS {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 == 1 {
+                std::thread::yield_now();
+                continue;
+            }
+
+            let stats = Stats {
+                allocs:     self.allocs.load(Ordering::Relaxed),
+                frees:      self.frees.load(Ordering::Relaxed),
+                live_bytes: self.live_bytes.load(Ordering::Relaxed),
+                peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+                updated_ms: self.updated_ms.load(Ordering::Relaxed),
+            };
+
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == before {
+                return Some(stats);
+            }
+        }
+
+        None
+    }
+}
+
+
+/// Name of the shared memory segment published by process `pid`.
+pub fn segment_name(pid: u32) -> String { format!("/sl-alloc.{}", pid) }
+
+fn open(pid: u32, flags: libc::c_int) -> io::Result<libc::c_int> {
+    let name = CString::new(segment_name(pid)).expect("segment name has no NULs");
+
+    // SAFETY: `name` is a valid C string.
+    let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o644) };
+    match fd {
+        | -1 => Err(io::Error::last_os_error()),
+        | fd => Ok(fd),
+    }
+}
+
+unsafe fn map(fd: libc::c_int, prot: libc::c_int) -> io::Result<*mut Segment> {
+    let ptr = libc::mmap(
+        std::ptr::null_mut(),
+        std::mem::size_of::<Segment>(),
+        prot,
+        libc::MAP_SHARED,
+        fd,
+        0,
+    );
+    libc::close(fd);
+
+    match ptr {
+        | libc::MAP_FAILED => Err(io::Error::last_os_error()),
+        | ptr => Ok(ptr.cast()),
+    }
+}
+
+
+//
+// Reader side
+//
+
+/// Read-only view of the stats published by another process.
+pub struct StatsView {
+    segment: *const Segment,
+    pid:     u32,
+}
+
+// SAFETY: the mapping is only ever read through atomics.
+unsafe impl Send for StatsView {}
+unsafe impl Sync for StatsView {}
+
+impl StatsView {
+    /// Attaches to the segment published by process `pid`.
+    pub fn attach(pid: u32) -> io::Result<Self> {
+        let fd = open(pid, libc::O_RDONLY)?;
+
+        // SAFETY: `fd` is open; `stat` is plain old data.
+        let size = unsafe {
+            let mut stat: libc::stat = std::mem::zeroed();
+            if libc::fstat(fd, &mut stat) == -1 {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(err);
+            }
+            stat.st_size as usize
+        };
+
+        if size < std::mem::size_of::<Segment>() {
+            // SAFETY: `fd` is open.
+            unsafe { libc::close(fd) };
+            return Err(invalid("segment is too small"));
+        }
+
+        // SAFETY: the file is at least as large as the mapping.
+        let segment = unsafe { map(fd, libc::PROT_READ)? };
+        let view = Self { segment, pid };
+
+        // SAFETY: mapped above, unmapped only on drop.
+        let seg = unsafe { &*view.segment };
+        if seg.magic.load(Ordering::Acquire) != MAGIC {
+            return Err(invalid("not an sl-alloc stats segment (or not ready yet)"));
+        }
+        if seg.version != VERSION {
+            return Err(invalid("unsupported stats segment version"));
+        }
+
+        Ok(view)
+    }
+
+    pub fn pid(&self) -> u32 { self.pid }
+
+    /// How often the publisher refreshes the segment.
+    pub fn interval_ms(&self) -> u64 { self.segment().interval_ms.load(Ordering::Relaxed) }
+
+    /// Latest sample. Fails if the segment stays mid-update (the
+    /// publisher is stuck or died while writing); retrying later is fine.
+    pub fn read(&self) -> io::Result<Stats> {
+        self.segment()
+            .read()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "stats segment is stuck mid-update"))
+    }
+
+    fn segment(&self) -> &Segment {
+        // SAFETY: mapped in `attach`, unmapped only on drop.
+        unsafe { &*self.segment }
+    }
+}
+
+impl Drop for StatsView {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `attach` with this exact size.
+        unsafe { libc::munmap(self.segment as *mut _, std::mem::size_of::<Segment>()) };
+    }
+}
+
+fn invalid(msg: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, msg) }
+
+
+//
+// Publisher side (needs the allocator hooks)
+//
+cfg_alloc_any! {
+    use std::{
+        sync::{
+            atomic::AtomicUsize,
+            Mutex,
+        },
+        time::{
+            Duration,
+            SystemTime,
+            UNIX_EPOCH,
+        },
+    };
+
+    use super::{
+        watermark,
+        Counter,
+        ShardedCounter,
+    };
+
+
+    const DEFAULT_INTERVAL_MS: u64 = 100;
+
+    static ALLOCS: ShardedCounter = ShardedCounter::ZERO;
+    static FREES: ShardedCounter = ShardedCounter::ZERO;
+    static INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL_MS);
+    // Address of the mapped segment (0 until published)
+    static SEGMENT: AtomicUsize = AtomicUsize::new(0);
+    static PUBLISH: Mutex<()> = Mutex::new(());
+
+
+    /// Publishes this process's allocator stats as `/sl-alloc.<pid>` and
+    /// starts the helper thread that keeps them fresh. Calling it again is
+    /// a no-op.
+    pub fn publish() -> io::Result<()> {
+        let _guard = PUBLISH.lock().expect("unable to lock shm publisher");
+        if SEGMENT.load(Ordering::Acquire) != 0 {
+            return Ok(());
+        }
+
+        let pid = std::process::id();
+        let fd = open(pid, libc::O_CREAT | libc::O_RDWR)?;
+
+        // SAFETY: `fd` is open; `stat` is plain old data. A segment left
+        // under this pid (by an earlier process with the same pid) is never
+        // shrunk, as readers still mapping it would fault; every field is
+        // rewritten below.
+        let segment = unsafe {
+            let size = std::mem::size_of::<Segment>();
+            let mut stat: libc::stat = std::mem::zeroed();
+            if libc::fstat(fd, &mut stat) == -1
+                || (stat.st_size < size as libc::off_t && libc::ftruncate(fd, size as libc::off_t) == -1)
+            {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(err);
+            }
+
+            map(fd, libc::PROT_READ | libc::PROT_WRITE)?
+        };
+
+        // SAFETY: mapped above and never unmapped.
+        unsafe {
+            std::ptr::addr_of_mut!((*segment).version).write(VERSION);
+            std::ptr::addr_of_mut!((*segment).pid).write(pid);
+
+            let seg = &*segment;
+            // Restart the sequence; one left behind may be odd (a publisher
+            // that died mid-write) or anything at all.
+            seg.seq.store(0, Ordering::Relaxed);
+            seg.interval_ms.store(INTERVAL_MS.load(Ordering::Relaxed), Ordering::Relaxed);
+            seg.write(&sample(0));
+            seg.magic.store(MAGIC, Ordering::Release);
+        }
+
+        let addr = segment as usize;
+        SEGMENT.store(addr, Ordering::Release);
+
+        std::thread::Builder::new()
+            .name("sl-shm-stats".to_string())
+            .spawn(move || refresh(addr))?;
+
+        Ok(())
+    }
+
+    /// Removes the segment so it stops showing up under /dev/shm and stops
+    /// the helper thread. Readers already attached keep seeing the last
+    /// sample.
+    pub fn unpublish() -> io::Result<()> {
+        let _guard = PUBLISH.lock().expect("unable to lock shm publisher");
+        if SEGMENT.swap(0, Ordering::AcqRel) == 0 {
+            return Ok(());
+        }
+
+        let name = CString::new(segment_name(std::process::id())).expect("segment name has no NULs");
+
+        // SAFETY: `name` is a valid C string.
+        match unsafe { libc::shm_unlink(name.as_ptr()) } {
+            | -1 => Err(io::Error::last_os_error()),
+            | _ => Ok(()),
+        }
+    }
+
+    /// Sets how often the helper thread refreshes the published stats.
+    pub fn set_publish_interval(interval: Duration) {
+        INTERVAL_MS.store(interval.as_millis().max(1) as u64, Ordering::Relaxed);
+    }
+
+
+    // The mapping is left in place after `unpublish`; it's a single page
+    // and attached readers may still be looking at it.
+    fn refresh(addr: usize) {
+        // SAFETY: mapped by `publish` and never unmapped.
+        let seg = unsafe { &*(addr as *const Segment) };
+        let mut peak = 0;
+
+        loop {
+            let interval = INTERVAL_MS.load(Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(interval));
+
+            if SEGMENT.load(Ordering::Acquire) != addr {
+                return;
+            }
+
+            let stats = sample(peak);
+            peak = stats.peak_bytes;
+
+            seg.interval_ms.store(interval, Ordering::Relaxed);
+            seg.write(&stats);
+        }
+    }
+
+    impl Segment {
+        // Single writer: `publish`, then the helper thread.
+        fn write(&self, stats: &Stats) {
+            let seq = self.seq.load(Ordering::Relaxed);
+            self.seq.store(seq + 1, Ordering::Relaxed);
+            fence(Ordering::Release);
+
+            self.allocs.store(stats.allocs, Ordering::Relaxed);
+            self.frees.store(stats.frees, Ordering::Relaxed);
+            self.live_bytes.store(stats.live_bytes, Ordering::Relaxed);
+            self.peak_bytes.store(stats.peak_bytes, Ordering::Relaxed);
+            self.updated_ms.store(stats.updated_ms, Ordering::Relaxed);
+
+            self.seq.store(seq + 2, Ordering::Release);
+        }
+    }
+
+    fn sample(peak: u64) -> Stats {
+        // Never negative (racing frees are clamped by the counter), so it
+        // can't wrap and pin the peak near u64::MAX.
+        let live_bytes = watermark::live_bytes() as u64;
+
+        Stats {
+            allocs: ALLOCS.get() as u64,
+            frees: FREES.get() as u64,
+            live_bytes,
+            peak_bytes: peak.max(live_bytes),
+            updated_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+        }
+    }
+
+    //
+    // Hooks called from the allocators
+    //
+    #[inline]
+    pub(crate) fn record_alloc() { ALLOCS.add(1); }
+
+    #[inline]
+    pub(crate) fn record_dealloc() { FREES.add(1); }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn segment(seq: u64) -> Segment {
+        Segment {
+            magic:       AtomicU64::new(MAGIC),
+            version:     VERSION,
+            pid:         1,
+            interval_ms: AtomicU64::new(100),
+            seq:         AtomicU64::new(seq),
+            allocs:      AtomicU64::new(10),
+            frees:       AtomicU64::new(4),
+            live_bytes:  AtomicU64::new(512),
+            peak_bytes:  AtomicU64::new(1024),
+            updated_ms:  AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn read_settled_segment() {
+        let stats = segment(2).read().unwrap();
+        assert_eq!((stats.allocs, stats.frees, stats.live_bytes, stats.peak_bytes), (10, 4, 512, 1024));
+        assert_eq!(stats.live_blocks(), 6);
+    }
+
+    #[test]
+    fn read_gives_up_on_unfinished_write() { assert!(segment(3).read().is_none()); }
+}
@@ -0,0 +1,301 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/slack
+ *
+ * Purpose:
+ *    Tracker that reports internal fragmentation per call site: bytes lost
+ *    to size-class rounding and to over-aligned requests.
+ *
+ *    By default the waste is estimated from the `Layout` alone using a
+ *    glibc-like model (one word of chunk overhead, two word granularity,
+ *    four word minimum chunk; anything aligned above the malloc alignment
+ *    may need up to `align - MIN_ALIGN` bytes of padding). Given a usable
+ *    size query for the inner allocator (e.g. `malloc_usable_size` for
+ *    `System` on Linux) the rounding is measured instead.
+ *
+ */
+
+use std::{
+    alloc::Layout,
+    collections::BTreeMap,
+};
+
+use super::{
+    filter::FilterPolicy,
+    stacks,
+    tracker,
+    Tracker,
+};
+
+
+const WORD: usize = std::mem::size_of::<usize>();
+const MIN_ALIGN: usize = 2 * WORD;
+const MIN_CHUNK: usize = 4 * WORD;
+
+const STACK_DEPTH: usize = 32;
+
+
+/// Usable size query for blocks handed out by the inner allocator.
+pub type UsableSizeFn = unsafe fn(*mut u8) -> usize;
+
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    #[link_name = "malloc_usable_size"]
+    fn libc_malloc_usable_size(ptr: *mut std::ffi::c_void) -> usize;
+}
+
+/// Usable size of a block from the system allocator.
+///
+/// # Safety
+/// `ptr` must have come from `malloc` (i.e. `std::alloc::System`).
+#[cfg(target_os = "linux")]
+pub unsafe fn malloc_usable_size(ptr: *mut u8) -> usize { libc_malloc_usable_size(ptr.cast()) }
+
+
+//
+// Bytes requested vs. wasted for one or more allocations
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Slack {
+    pub requested: usize,
+    pub rounding:  usize,
+    pub alignment: usize,
+}
+
+impl Slack {
+    /// Estimates the waste for a single request.
+    pub fn estimate(layout: Layout) -> Self {
+        let chunk = (layout.size() + WORD).max(MIN_CHUNK).next_multiple_of(MIN_ALIGN);
+
+        Self {
+            requested: layout.size(),
+            rounding:  chunk - WORD - layout.size(),
+            alignment: layout.align().saturating_sub(MIN_ALIGN),
+        }
+    }
+
+    /// Waste for a single request given the block's actual usable size.
+    pub fn measured(layout: Layout, usable: usize) -> Self {
+        Self {
+            requested: layout.size(),
+            rounding:  usable.saturating_sub(layout.size()),
+            alignment: layout.align().saturating_sub(MIN_ALIGN),
+        }
+    }
+
+    pub fn wasted(&self) -> usize { self.rounding + self.alignment }
+
+    pub fn actual(&self) -> usize { self.requested + self.wasted() }
+
+    fn add(&mut self, other: &Slack) {
+        self.requested += other.requested;
+        self.rounding += other.rounding;
+        self.alignment += other.alignment;
+    }
+
+    fn sub(&mut self, other: &Slack) {
+        self.requested -= other.requested;
+        self.rounding -= other.rounding;
+        self.alignment -= other.alignment;
+    }
+}
+
+impl std::fmt::Display for Slack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pct = match self.actual() {
+            | 0 => 0.0,
+            | actual => self.wasted() as f64 * 100.0 / actual as f64,
+        };
+
+        write!(
+            f,
+            "requested {} bytes, actual {} bytes, wasted {} bytes ({:.1}%: rounding {}, alignment {})",
+            self.requested,
+            self.actual(),
+            self.wasted(),
+            pct,
+            self.rounding,
+            self.alignment,
+        )
+    }
+}
+
+
+//
+// Per call site totals
+//
+struct Site {
+    ips:    Vec<usize>,
+    allocs: usize,
+    total:  Slack,
+    live:   Slack,
+}
+
+
+pub struct SlackTracker {
+    usable: Option<UsableSizeFn>,
+    depth:  usize,
+    sites:  Vec<Site>,
+    by_ips: BTreeMap<Vec<usize>, usize>,
+    // Live block => (site, slack)
+    live:   BTreeMap<usize, (usize, Slack)>,
+}
+
+impl SlackTracker {
+    /// Estimates waste from the requested layouts alone.
+    pub const fn new() -> Self { Self::build(None) }
+
+    /// Measures rounding with `usable` instead of estimating it.
+    ///
+    /// # Safety
+    /// `usable` is called on every block the tracker sees, so it must be
+    /// valid for any pointer the wrapped allocator returns (e.g.
+    /// `malloc_usable_size` only when the inner allocator is `System`).
+    pub const unsafe fn with_usable_size(usable: UsableSizeFn) -> Self { Self::build(Some(usable)) }
+
+    const fn build(usable: Option<UsableSizeFn>) -> Self {
+        Self {
+            usable,
+            depth: STACK_DEPTH,
+            sites: Vec::new(),
+            by_ips: BTreeMap::new(),
+            live: BTreeMap::new(),
+        }
+    }
+
+    /// Waste across every live block.
+    pub fn live(&self) -> Slack {
+        let mut slack = Slack::default();
+        for site in self.sites.iter() {
+            slack.add(&site.live);
+        }
+        slack
+    }
+
+    fn site(&mut self) -> usize {
+        let mut ips = Vec::with_capacity(self.depth);
+        backtrace::trace(|frame| {
+            ips.push(frame.ip() as usize);
+            ips.len() < self.depth
+        });
+
+        if let Some(idx) = self.by_ips.get(&ips) {
+            return *idx;
+        }
+
+        let idx = self.sites.len();
+        self.by_ips.insert(ips.clone(), idx);
+        self.sites.push(Site {
+            ips,
+            allocs: 0,
+            total: Slack::default(),
+            live: Slack::default(),
+        });
+        idx
+    }
+
+    fn insert(&mut self, ptr: *mut u8, layout: Layout) {
+        let slack = match self.usable {
+            // SAFETY: `ptr` was just returned by the inner allocator.
+            | Some(usable) => Slack::measured(layout, unsafe { usable(ptr) }),
+            | None => Slack::estimate(layout),
+        };
+
+        let idx = self.site();
+        let site = &mut self.sites[idx];
+        site.allocs += 1;
+        site.total.add(&slack);
+        site.live.add(&slack);
+
+        self.live.insert(ptr as usize, (idx, slack));
+    }
+
+    fn remove(&mut self, ptr: *mut u8) {
+        // Blocks from before tracking started are unknown.
+        if let Some((idx, slack)) = self.live.remove(&(ptr as usize)) {
+            self.sites[idx].live.sub(&slack);
+        }
+    }
+}
+
+impl Default for SlackTracker {
+    fn default() -> Self { Self::new() }
+}
+
+impl Tracker for SlackTracker {
+    fn dump_info(
+        &mut self,
+        out: &mut dyn std::io::Write,
+        filter: &FilterPolicy,
+    ) -> std::io::Result<()> {
+        let mut total = Slack::default();
+        for site in self.sites.iter() {
+            total.add(&site.total);
+        }
+
+        writeln!(out, "=============== ALLOCATION SLACK ===============")?;
+        writeln!(
+            out,
+            "mode: {}",
+            if self.usable.is_some() { "measured" } else { "estimated" }
+        )?;
+        writeln!(out, "live:  {}", self.live())?;
+        writeln!(out, "total: {}", total)?;
+
+        let mut order: Vec<usize> = (0..self.sites.len()).collect();
+        order.sort_by_key(|idx| std::cmp::Reverse(self.sites[*idx].total.wasted()));
+
+        writeln!(out, "\n\n=============== SLACK BY CALL SITE ===============")?;
+        for idx in order {
+            let site = &self.sites[idx];
+            if site.total.wasted() == 0 {
+                continue;
+            }
+
+            let Some(frames) = filter.frames(&stacks::resolve_ips(&site.ips)) else {
+                continue;
+            };
+
+            writeln!(
+                out,
+                "{} allocations ({} live bytes): {}",
+                site.allocs, site.live.requested, site.total
+            )?;
+            for frame in frames {
+                writeln!(out, "   > {frame}")?;
+            }
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_filter(&mut self, filter: &FilterPolicy) {
+        self.depth = tracker::capture_depth(filter).min(STACK_DEPTH);
+    }
+
+    fn track_alloc(&mut self, _id: usize, ptr: *mut u8, layout: Layout) {
+        if !ptr.is_null() {
+            self.insert(ptr, layout);
+        }
+    }
+
+    fn track_dealloc(&mut self, ptr: *mut u8, _layout: Layout) { self.remove(ptr); }
+
+    // The new block is charged to the site that resized it.
+    fn track_realloc(
+        &mut self,
+        _id: usize,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        _old_layout: Layout,
+        new_layout: Layout,
+    ) {
+        self.remove(old_ptr);
+        self.insert(new_ptr, new_layout);
+    }
+}
@@ -0,0 +1,144 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/spans
+ *
+ * Purpose:
+ *    `tracing_subscriber` layer that attributes allocations to spans. Each
+ *    thread keeps running totals fed by the installed sl_core allocator;
+ *    the layer snapshots them when a span is entered and adds the
+ *    difference to the span when it is exited. Totals include anything
+ *    allocated by child spans.
+ *
+ *    The totals are kept in the span's extensions as an `AllocCounts`
+ *    (`polls` counts how many times the span was entered) so other layers
+ *    can read them, and are emitted as an event when the span closes.
+ *
+ */
+
+use std::cell::{
+    Cell,
+    RefCell,
+};
+
+use tracing::{
+    span,
+    Subscriber,
+};
+use tracing_subscriber::{
+    layer::Context,
+    registry::LookupSpan,
+    Layer,
+};
+
+use super::AllocCounts;
+
+
+thread_local! {
+    static TOTALS: Cell<AllocCounts> = const {
+        Cell::new(AllocCounts {
+            allocs:      0,
+            frees:       0,
+            bytes_alloc: 0,
+            bytes_freed: 0,
+            polls:       0,
+        })
+    };
+
+    // Spans entered on this thread along with the totals at entry
+    static ENTERED: RefCell<Vec<(span::Id, AllocCounts)>> = const { RefCell::new(Vec::new()) };
+}
+
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllocLayer {
+    _priv: (),
+}
+
+impl AllocLayer {
+    pub const fn new() -> Self { Self { _priv: () } }
+}
+
+impl<S> Layer<S> for AllocLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(AllocCounts::default());
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, _ctx: Context<'_, S>) {
+        let now = TOTALS.with(|t| t.get());
+        ENTERED.with(|e| e.borrow_mut().push((id.clone(), now)));
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        // Spans are not required to exit in the order they were entered.
+        let entered = ENTERED.with(|e| {
+            let mut e = e.borrow_mut();
+            e.iter()
+                .rposition(|(i, _)| i == id)
+                .map(|pos| e.remove(pos).1)
+        });
+
+        let (Some(before), Some(span)) = (entered, ctx.span(id)) else {
+            return;
+        };
+
+        let delta = TOTALS.with(|t| t.get()).delta(&before);
+        let mut ext = span.extensions_mut();
+        if let Some(counts) = ext.get_mut::<AllocCounts>() {
+            counts.merge(&delta);
+            counts.polls += 1;
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+
+        let Some(c) = span.extensions().get::<AllocCounts>().copied() else {
+            return;
+        };
+
+        tracing::info!(
+            target: "sl_core::allocators",
+            span = span.name(),
+            allocs = c.allocs,
+            active = c.active(),
+            bytes = c.bytes_alloc,
+            active_bytes = c.active_bytes(),
+            entered = c.polls,
+            "span allocations",
+        );
+    }
+}
+
+
+//
+// Hooks called from the allocators
+//
+#[inline]
+pub(crate) fn record_alloc(size: usize) {
+    let _ = TOTALS.try_with(|t| {
+        let mut totals = t.get();
+        totals.allocs += 1;
+        totals.bytes_alloc += size;
+        t.set(totals);
+    });
+}
+
+#[inline]
+pub(crate) fn record_dealloc(size: usize) {
+    let _ = TOTALS.try_with(|t| {
+        let mut totals = t.get();
+        totals.frees += 1;
+        totals.bytes_freed += size;
+        t.set(totals);
+    });
+}
@@ -0,0 +1,179 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/stacks
+ *
+ * Purpose:
+ *    Stack capture, interning and symbol resolution shared by the
+ *    trackers.
+ *
+ *    Allocation stacks repeat a lot, so trackers store each unique stack
+ *    (keyed by its instruction addresses) once in a `StackTable` and refer
+ *    to it by id. Symbols are resolved per instruction address through a
+ *    process-wide cache, so a frame that shows up in thousands of stacks
+ *    is resolved once.
+ *
+ *    Stacks are captured with the regular unwinder by default. The frame
+ *    pointer walker is far cheaper, but only sees frames that keep a frame
+ *    pointer: build with `-C force-frame-pointers=yes` (and std with it,
+ *    via `-Zbuild-std`, to see through std frames). It stops at the first
+ *    frame that doesn't look like one, but that can't catch every broken
+ *    chain, so picking it is unsafe. Architectures other than x86_64 /
+ *    aarch64 always unwind.
+ *
+ */
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+};
+
+use super::dump::Symbol;
+
+
+// Largest gap between two frame pointers the walker accepts.
+const MAX_FRAME_SIZE: usize = 1 << 20;
+
+// Instruction address => symbols (more than one when inlined)
+static SYMBOLS: Mutex<BTreeMap<usize, Vec<Symbol>>> = Mutex::new(BTreeMap::new());
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackCapture {
+    // Full unwind (DWARF CFI); always accurate
+    Unwind,
+    // Walk the frame pointer chain; needs frame pointers everywhere
+    FramePointers,
+}
+
+
+//
+// Captured stacks
+//
+pub(super) type StackId = u32;
+
+pub(super) struct StackTable {
+    stacks:  Vec<Box<[usize]>>,
+    index:   BTreeMap<Box<[usize]>, StackId>,
+    // Reused for every capture so lookups of known stacks don't allocate
+    scratch: Vec<usize>,
+}
+
+impl StackTable {
+    pub(super) const fn new() -> Self {
+        Self {
+            stacks:  Vec::new(),
+            index:   BTreeMap::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Captures the current stack (up to `depth` frames) and interns it.
+    #[inline(never)]
+    pub(super) fn capture(&mut self, mode: StackCapture, depth: usize) -> StackId {
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.clear();
+        capture(mode, depth, &mut scratch);
+
+        let id = self.intern(&scratch);
+        self.scratch = scratch;
+        id
+    }
+
+    pub(super) fn intern(&mut self, ips: &[usize]) -> StackId {
+        if let Some(id) = self.index.get(ips) {
+            return *id;
+        }
+
+        let id = self.stacks.len() as StackId;
+        self.stacks.push(ips.into());
+        self.index.insert(ips.into(), id);
+        id
+    }
+
+    pub(super) fn get(&self, id: StackId) -> &[usize] { &self.stacks[id as usize] }
+
+    pub(super) fn len(&self) -> usize { self.stacks.len() }
+}
+
+
+/// Appends up to `depth` return addresses of the current stack to `out`.
+pub(super) fn capture(mode: StackCapture, depth: usize, out: &mut Vec<usize>) {
+    match mode {
+        | StackCapture::FramePointers if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) => {
+            walk_frame_pointers(depth, out)
+        },
+        | _ => backtrace::trace(|frame| {
+            out.push(frame.ip() as usize);
+            out.len() < depth
+        }),
+    }
+}
+
+#[inline(never)]
+fn walk_frame_pointers(depth: usize, out: &mut Vec<usize>) {
+    let mut fp: usize = 0;
+
+    // SAFETY: only reads the frame pointer register.
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+
+    // The first frame has to be just above our own locals.
+    let sp = &fp as *const usize as usize;
+    if fp < sp || fp - sp > MAX_FRAME_SIZE {
+        return;
+    }
+
+    while out.len() < depth && fp != 0 && fp.is_multiple_of(std::mem::align_of::<usize>()) {
+        // SAFETY: the walker is only picked through the unsafe
+        // `DefaultTracker::with_capture`, whose caller guarantees frame
+        // pointers everywhere, so `fp` is a frame record on this stack.
+        let (next, ret) = unsafe {
+            let frame = fp as *const usize;
+            (*frame, *frame.add(1))
+        };
+
+        if ret == 0 {
+            break;
+        }
+        out.push(ret);
+
+        if next <= fp || next - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = next;
+    }
+}
+
+
+/// Symbols for a list of instruction addresses, resolving each address
+/// only the first time it is seen (by anyone in the process).
+pub(super) fn resolve_ips(ips: &[usize]) -> Vec<Symbol> {
+    let mut cache = SYMBOLS.lock().expect("unable to lock symbol cache");
+
+    let mut symbols = Vec::new();
+    for ip in ips {
+        let resolved = cache.entry(*ip).or_insert_with(|| {
+            let mut syms = Vec::new();
+            backtrace::resolve(*ip as *mut std::ffi::c_void, |sym| {
+                syms.push(Symbol {
+                    name:     sym.name().map(|n| n.to_string()),
+                    filename: sym.filename().map(|f| f.display().to_string()),
+                    lineno:   sym.lineno(),
+                });
+            });
+            syms
+        });
+        symbols.extend(resolved.iter().cloned());
+    }
+
+    symbols
+}
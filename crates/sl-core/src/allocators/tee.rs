@@ -0,0 +1,247 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/tee
+ *
+ * Purpose:
+ *    Fan-out tracker. Forwards every event to a list of boxed trackers, so
+ *    one run can produce e.g. a leak report, a slack report and a replay
+ *    trace together. The list can be built in code or from a spec string
+ *    such as "default,slack,flight", including lazily from an environment
+ *    variable (see `Tracing::from_env`):
+ *
+ *      default         DefaultTracker (resolved report)
+ *      raw             DefaultTracker (raw dump for sl-symbolize)
+ *      recorder        Recorder (replay trace)
+ *      flight          FlightRecorder (last 1024 operations)
+ *      flight-stacks   FlightRecorder, with stacks
+ *      slack           SlackTracker (measured on Linux under `Tracing::from_env`,
+ *                      estimated otherwise)
+ *
+ *    Reports are written one after the other to the same output.
+ *
+ */
+
+use std::{
+    alloc::Layout,
+    io,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
+use super::{
+    dump::Snapshot,
+    filter::FilterPolicy,
+    slack::UsableSizeFn,
+    DefaultTracker,
+    FlightRecorder,
+    Recorder,
+    SlackTracker,
+    Tracker,
+};
+
+
+const FLIGHT_SLOTS: usize = 1024;
+
+// A bad spec is found under the tracker lock, where logging could deadlock
+// against the logger, so the warning waits for `log_bad_spec`.
+static BAD_SPEC: Mutex<Option<String>> = Mutex::new(None);
+static HAS_BAD_SPEC: AtomicBool = AtomicBool::new(false);
+
+
+pub type DynTracker = Box<dyn Tracker + Send>;
+
+
+pub struct Tee {
+    trackers: Vec<DynTracker>,
+    // Spec still to be read from the environment: (variable, fallback)
+    pending:  Option<(&'static str, &'static str)>,
+    // Usable size query for "slack"; only set when the inner allocator is
+    // known to be `System`.
+    usable:   Option<UsableSizeFn>,
+}
+
+impl Tee {
+    pub const fn new() -> Self {
+        Self {
+            trackers: Vec::new(),
+            pending:  None,
+            usable:   None,
+        }
+    }
+
+    /// Builds the trackers from the spec in environment variable `var` (or
+    /// `fallback` when it isn't set) the first time the tee is used. A bad
+    /// spec is logged and falls back as well.
+    pub const fn from_env(var: &'static str, fallback: &'static str) -> Self {
+        Self {
+            trackers: Vec::new(),
+            pending:  Some((var, fallback)),
+            usable:   None,
+        }
+    }
+
+    // `from_env` for a tee paired with `System`, where slack can be
+    // measured with `malloc_usable_size`.
+    pub(super) const fn from_env_system(var: &'static str, fallback: &'static str) -> Self {
+        Self {
+            trackers: Vec::new(),
+            pending:  Some((var, fallback)),
+            #[cfg(target_os = "linux")]
+            usable: Some(super::malloc_usable_size),
+            #[cfg(not(target_os = "linux"))]
+            usable: None,
+        }
+    }
+
+    /// Builds the trackers from a comma separated list of tracker names.
+    pub fn from_spec(spec: &str) -> io::Result<Self> { Self::build(spec, None) }
+
+    fn build(spec: &str, usable: Option<UsableSizeFn>) -> io::Result<Self> {
+        let mut tee = Self::new();
+        for name in spec.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            tee.push(named(name, usable)?);
+        }
+
+        Ok(tee)
+    }
+
+    pub fn with<T: Tracker + Send + 'static>(mut self, tracker: T) -> Self {
+        self.push(Box::new(tracker));
+        self
+    }
+
+    pub fn push(&mut self, tracker: DynTracker) { self.trackers.push(tracker); }
+
+    pub fn len(&self) -> usize { self.trackers.len() }
+
+    pub fn is_empty(&self) -> bool { self.trackers.is_empty() }
+
+    fn trackers(&mut self) -> &mut Vec<DynTracker> {
+        if let Some((var, fallback)) = self.pending.take() {
+            let spec = std::env::var(var).unwrap_or_else(|_| fallback.to_string());
+            let tee = Self::build(&spec, self.usable).unwrap_or_else(|e| {
+                if let Ok(mut bad) = BAD_SPEC.lock() {
+                    *bad = Some(format!("ignoring {}='{}': {}", var, spec, e));
+                    HAS_BAD_SPEC.store(true, Ordering::Release);
+                }
+                Self::build(fallback, self.usable).unwrap_or_default()
+            });
+            self.trackers.extend(tee.trackers);
+        }
+
+        &mut self.trackers
+    }
+}
+
+
+/// Logs a bad environment spec found by a tee. Call without the tracker
+/// lock held. The spec is usually read on the first allocation, before a
+/// logger is installed, so the warning is held until one is.
+pub(super) fn log_bad_spec() {
+    if !HAS_BAD_SPEC.load(Ordering::Acquire) || !log::log_enabled!(log::Level::Warn) {
+        return;
+    }
+    if !HAS_BAD_SPEC.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    if let Some(msg) = BAD_SPEC.lock().ok().and_then(|mut bad| bad.take()) {
+        log::warn!("{}", msg);
+    }
+}
+
+impl Default for Tee {
+    fn default() -> Self { Self::new() }
+}
+
+impl Tracker for Tee {
+    fn dump_info(&mut self, out: &mut dyn io::Write, filter: &FilterPolicy) -> io::Result<()> {
+        for (idx, tracker) in self.trackers().iter_mut().enumerate() {
+            if idx > 0 {
+                writeln!(out)?;
+            }
+            tracker.dump_info(out, filter)?;
+        }
+
+        Ok(())
+    }
+
+    fn track_alloc(&mut self, id: usize, ptr: *mut u8, layout: Layout) {
+        for tracker in self.trackers() {
+            tracker.track_alloc(id, ptr, layout);
+        }
+    }
+
+    fn track_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        for tracker in self.trackers() {
+            tracker.track_dealloc(ptr, layout);
+        }
+    }
+
+    fn track_alloc_zeroed(&mut self, id: usize, ptr: *mut u8, layout: Layout) {
+        for tracker in self.trackers() {
+            tracker.track_alloc_zeroed(id, ptr, layout);
+        }
+    }
+
+    fn track_realloc(
+        &mut self,
+        id: usize,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) {
+        for tracker in self.trackers() {
+            tracker.track_realloc(id, old_ptr, new_ptr, old_layout, new_layout);
+        }
+    }
+
+    // The first tracker that keeps per-allocation state provides it.
+    fn snapshot(&mut self, name: &str, filter: &FilterPolicy) -> Snapshot {
+        self.trackers()
+            .iter_mut()
+            .map(|tracker| tracker.snapshot(name, filter))
+            .find(|snap| !snap.live.is_empty())
+            .unwrap_or_else(|| Snapshot::new(name))
+    }
+
+    fn set_filter(&mut self, filter: &FilterPolicy) {
+        for tracker in self.trackers() {
+            tracker.set_filter(filter);
+        }
+    }
+}
+
+
+fn named(name: &str, usable: Option<UsableSizeFn>) -> io::Result<DynTracker> {
+    let tracker: DynTracker = match name {
+        | "default" => Box::new(DefaultTracker::new()),
+        | "raw" => Box::new(DefaultTracker::raw()),
+        | "recorder" => Box::new(Recorder::new()),
+        | "flight" => FlightRecorder::<FLIGHT_SLOTS>::boxed(),
+        | "flight-stacks" => FlightRecorder::<FLIGHT_SLOTS>::boxed_with_stacks(),
+        // SAFETY: `usable` is only set by `from_env_system`, whose tee sits
+        // over `System`.
+        | "slack" => match usable {
+            | Some(usable) => Box::new(unsafe { SlackTracker::with_usable_size(usable) }),
+            | None => Box::new(SlackTracker::new()),
+        },
+        | _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown tracker '{}'", name),
+            ))
+        },
+    };
+
+    Ok(tracker)
+}
@@ -0,0 +1,455 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/timeline
+ *
+ * Purpose:
+ *    Live memory over time. While a timeline is recording, samples of the
+ *    live byte count and the number of allocations / frees so far are
+ *    taken on a helper thread at a fixed interval and / or on demand
+ *    (`sample`), and user code can mark phases of the run with named
+ *    checkpoints:
+ *
+ *      timeline::start(Sampling::Every(Duration::from_millis(5)));
+ *      timeline::checkpoint("load");
+ *      ...
+ *      timeline::stop().unwrap().write_html(&mut file)?;
+ *
+ *    The result is exported as CSV (one row per sample, checkpoints in
+ *    the last column) or as a self-contained SVG / HTML chart of live
+ *    bytes and allocation rate with the checkpoints drawn across both.
+ *    Sawtooth patterns, steady growth and spikes tied to a phase are
+ *    visible at a glance.
+ *
+ *    Samples are kept in memory until the timeline is stopped (roughly
+ *    64 bytes each; 100 per second at the default interval).
+ *
+ */
+
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use super::{
+    live_bytes,
+    Counter,
+    ShardedCounter,
+};
+
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(10);
+
+static ALLOCS: ShardedCounter = ShardedCounter::ZERO;
+static FREES: ShardedCounter = ShardedCounter::ZERO;
+static RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+// Bumped by every `start`, so samplers of earlier recordings stop
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+// Chart geometry (SVG user units)
+const WIDTH: f64 = 1000.0;
+const MARGIN_LEFT: f64 = 80.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const LIVE_TOP: f64 = 40.0;
+const LIVE_HEIGHT: f64 = 260.0;
+const RATE_TOP: f64 = LIVE_TOP + LIVE_HEIGHT + 30.0;
+const RATE_HEIGHT: f64 = 100.0;
+const HEIGHT: f64 = RATE_TOP + RATE_HEIGHT + 40.0;
+const TICKS: usize = 5;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sampling {
+    // Sample on a helper thread at this interval (plus `sample` /
+    // `checkpoint`)
+    Every(Duration),
+    // Only sample on `sample` / `checkpoint`
+    Manual,
+}
+
+impl Default for Sampling {
+    fn default() -> Self { Self::Every(DEFAULT_INTERVAL) }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    // Since the timeline was started
+    pub time:       Duration,
+    pub live_bytes: usize,
+    // Allocations / frees since the timeline was started
+    pub allocs:     usize,
+    pub frees:      usize,
+    pub checkpoint: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timeline {
+    pub samples: Vec<Sample>,
+}
+
+struct Recording {
+    generation: usize,
+    start:      Instant,
+    // Counter values when the timeline was started
+    allocs:     usize,
+    frees:      usize,
+    samples:    Vec<Sample>,
+}
+
+impl Recording {
+    fn sample(&mut self, checkpoint: Option<String>) {
+        self.samples.push(Sample {
+            time: self.start.elapsed(),
+            live_bytes: live_bytes(),
+            allocs: ALLOCS.get().wrapping_sub(self.allocs),
+            frees: FREES.get().wrapping_sub(self.frees),
+            checkpoint,
+        });
+    }
+}
+
+
+/// Starts recording a new timeline, discarding any recording in progress.
+pub fn start(sampling: Sampling) {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let mut recording = Recording {
+        generation,
+        start: Instant::now(),
+        allocs: ALLOCS.get(),
+        frees: FREES.get(),
+        samples: Vec::new(),
+    };
+    recording.sample(None);
+    *RECORDING.lock().expect("unable to lock timeline") = Some(recording);
+
+    if let Sampling::Every(interval) = sampling {
+        let interval = interval.max(Duration::from_millis(1));
+        std::thread::Builder::new()
+            .name("sl-timeline".to_string())
+            .spawn(move || sampler(generation, interval))
+            .expect("failed to spawn timeline thread");
+    }
+}
+
+/// Stops recording and returns the timeline (None if none was recording).
+pub fn stop() -> Option<Timeline> {
+    let mut recording = RECORDING.lock().expect("unable to lock timeline").take()?;
+    recording.sample(None);
+
+    Some(Timeline {
+        samples: recording.samples,
+    })
+}
+
+/// Copy of the timeline recorded so far.
+pub fn snapshot() -> Option<Timeline> {
+    RECORDING
+        .lock()
+        .expect("unable to lock timeline")
+        .as_ref()
+        .map(|r| Timeline {
+            samples: r.samples.clone(),
+        })
+}
+
+/// Takes a sample now (event-driven timelines, e.g. once per frame).
+pub fn sample() {
+    if let Some(recording) = RECORDING.lock().expect("unable to lock timeline").as_mut() {
+        recording.sample(None);
+    }
+}
+
+/// Takes a sample now, marked with `name`.
+pub fn checkpoint(name: &str) {
+    if let Some(recording) = RECORDING.lock().expect("unable to lock timeline").as_mut() {
+        recording.sample(Some(name.to_string()));
+    }
+}
+
+
+fn sampler(generation: usize, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+
+        match RECORDING.lock().expect("unable to lock timeline").as_mut() {
+            | Some(recording) if recording.generation == generation => recording.sample(None),
+            | _ => break,
+        }
+    }
+}
+
+
+impl Timeline {
+    pub fn duration(&self) -> Duration { self.samples.last().map(|s| s.time).unwrap_or_default() }
+
+    pub fn peak_bytes(&self) -> usize { self.samples.iter().map(|s| s.live_bytes).max().unwrap_or(0) }
+
+    /// Allocations per second between sample `idx - 1` and `idx`.
+    pub fn alloc_rate(&self, idx: usize) -> f64 {
+        if idx == 0 || idx >= self.samples.len() {
+            return 0.0;
+        }
+
+        let (prev, cur) = (&self.samples[idx - 1], &self.samples[idx]);
+        let secs = (cur.time - prev.time).as_secs_f64();
+        if secs <= 0.0 {
+            return 0.0;
+        }
+
+        cur.allocs.saturating_sub(prev.allocs) as f64 / secs
+    }
+
+    pub fn checkpoints(&self) -> impl Iterator<Item = (Duration, &str)> {
+        self.samples
+            .iter()
+            .filter_map(|s| s.checkpoint.as_deref().map(|name| (s.time, name)))
+    }
+
+    pub fn write_csv<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        writeln!(out, "time_ms,live_bytes,allocs,frees,alloc_rate,checkpoint")?;
+
+        for (idx, s) in self.samples.iter().enumerate() {
+            writeln!(
+                out,
+                "{:.3},{},{},{},{:.1},{}",
+                s.time.as_secs_f64() * 1000.0,
+                s.live_bytes,
+                s.allocs,
+                s.frees,
+                self.alloc_rate(idx),
+                s.checkpoint.as_deref().map(csv_field).unwrap_or_default()
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_svg<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        out.write_all(self.svg().as_bytes())
+    }
+
+    /// Standalone page with the chart and the list of checkpoints.
+    pub fn write_html<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) -> std::io::Result<()> {
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html><head><meta charset=\"utf-8\"><title>Memory timeline</title>")?;
+        writeln!(
+            out,
+            "<style>body {{ font-family: sans-serif; margin: 2em; }} td {{ padding: 0 1em; }}</style>"
+        )?;
+        writeln!(out, "</head><body>")?;
+        writeln!(
+            out,
+            "<h2>Memory timeline</h2><p>{} samples over {:.3} s, peak {}</p>",
+            self.samples.len(),
+            self.duration().as_secs_f64(),
+            human(self.peak_bytes() as f64)
+        )?;
+        self.write_svg(out)?;
+
+        writeln!(out, "<table><tr><th>time (ms)</th><th>checkpoint</th><th>live</th></tr>")?;
+        for s in self.samples.iter().filter(|s| s.checkpoint.is_some()) {
+            writeln!(
+                out,
+                "<tr><td>{:.3}</td><td>{}</td><td>{}</td></tr>",
+                s.time.as_secs_f64() * 1000.0,
+                escape(s.checkpoint.as_deref().unwrap_or_default()),
+                human(s.live_bytes as f64)
+            )?;
+        }
+        writeln!(out, "</table></body></html>")
+    }
+
+    fn svg(&self) -> String {
+        let mut svg = String::new();
+        let end = self.duration().as_secs_f64().max(f64::EPSILON);
+        let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+        let x = |t: Duration| MARGIN_LEFT + t.as_secs_f64() / end * plot_width;
+
+        let rates: Vec<f64> = (0..self.samples.len()).map(|idx| self.alloc_rate(idx)).collect();
+        let live: Vec<f64> = self.samples.iter().map(|s| s.live_bytes as f64).collect();
+        let times: Vec<Duration> = self.samples.iter().map(|s| s.time).collect();
+
+        let _ = writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{W}\" height=\"{H}\" viewBox=\"0 0 {W} {H}\" \
+             font-family=\"sans-serif\" font-size=\"11\">",
+            W = WIDTH,
+            H = HEIGHT
+        );
+        let _ = writeln!(svg, "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>");
+
+        let panels = [
+            ("live bytes", &live, LIVE_TOP, LIVE_HEIGHT, "#1f77b4", true),
+            ("allocs / s", &rates, RATE_TOP, RATE_HEIGHT, "#d62728", false),
+        ];
+        for (title, values, top, height, color, bytes) in panels {
+            let max = values.iter().cloned().fold(0.0, f64::max).max(1.0);
+            let y = |v: f64| top + height - v / max * height;
+
+            let _ = writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" font-weight=\"bold\">{}</text>",
+                MARGIN_LEFT,
+                top - 8.0,
+                title
+            );
+            for tick in 0..=TICKS {
+                let v = max * tick as f64 / TICKS as f64;
+                let label = if bytes { human(v) } else { format!("{:.0}", v) };
+                let _ = writeln!(
+                    svg,
+                    "<line x1=\"{l}\" x2=\"{r}\" y1=\"{y:.1}\" y2=\"{y:.1}\" stroke=\"#eee\"/>\
+                     <text x=\"{tx}\" y=\"{ty:.1}\" text-anchor=\"end\">{label}</text>",
+                    l = MARGIN_LEFT,
+                    r = WIDTH - MARGIN_RIGHT,
+                    y = y(v),
+                    tx = MARGIN_LEFT - 6.0,
+                    ty = y(v) + 4.0,
+                );
+            }
+            let _ = writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#999\"/>",
+                MARGIN_LEFT, top, plot_width, height
+            );
+
+            let points = envelope(&times, values, x)
+                .into_iter()
+                .map(|(px, v)| format!("{:.1},{:.1}", px, y(v)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let _ = writeln!(
+                svg,
+                "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.2\" points=\"{}\"/>",
+                color, points
+            );
+        }
+
+        // Time axis
+        for tick in 0..=TICKS * 2 {
+            let t = end * tick as f64 / (TICKS * 2) as f64;
+            let _ = writeln!(
+                svg,
+                "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{:.3} s</text>",
+                MARGIN_LEFT + t / end * plot_width,
+                RATE_TOP + RATE_HEIGHT + 16.0,
+                t
+            );
+        }
+
+        // Checkpoints across both panels
+        for (time, name) in self.checkpoints() {
+            let px = x(time);
+            let _ = writeln!(
+                svg,
+                "<g><title>{name} @ {ms:.3} ms</title>\
+                 <line x1=\"{px:.1}\" x2=\"{px:.1}\" y1=\"{top}\" y2=\"{bottom}\" stroke=\"#2ca02c\" \
+                 stroke-dasharray=\"4 3\"/>\
+                 <text x=\"{tx:.1}\" y=\"{ty}\" fill=\"#2ca02c\" text-anchor=\"end\" \
+                 transform=\"rotate(-90 {tx:.1} {ty})\">{name}</text></g>",
+                name = escape(name),
+                ms = time.as_secs_f64() * 1000.0,
+                top = LIVE_TOP,
+                bottom = RATE_TOP + RATE_HEIGHT,
+                tx = px - 3.0,
+                ty = LIVE_TOP + 4.0,
+            );
+        }
+
+        let _ = writeln!(svg, "</svg>");
+        svg
+    }
+}
+
+
+// Points to draw for a series: long timelines have far more samples than
+// pixels, so keep the first, minimum, maximum and last value of each
+// pixel column (spikes survive, unlike with plain decimation).
+fn envelope(times: &[Duration], values: &[f64], x: impl Fn(Duration) -> f64) -> Vec<(f64, f64)> {
+    let mut points: Vec<(f64, f64)> = Vec::new();
+    let mut column: Vec<(f64, f64)> = Vec::new();
+
+    let mut flush = |column: &mut Vec<(f64, f64)>| {
+        if column.is_empty() {
+            return;
+        }
+
+        let first = column[0];
+        let last = column[column.len() - 1];
+        let min = column.iter().cloned().fold(first, |m, p| if p.1 < m.1 { p } else { m });
+        let max = column.iter().cloned().fold(first, |m, p| if p.1 > m.1 { p } else { m });
+
+        let mut keep = vec![first, min, max, last];
+        keep.sort_by(|a, b| a.0.total_cmp(&b.0));
+        keep.dedup();
+        points.extend(keep);
+        column.clear();
+    };
+
+    for (time, value) in times.iter().zip(values) {
+        let px = x(*time);
+        if column.first().is_some_and(|p| p.0.floor() != px.floor()) {
+            flush(&mut column);
+        }
+        column.push((px, *value));
+    }
+    flush(&mut column);
+
+    points
+}
+
+fn human(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{:.0} B", value)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+
+//
+// Hooks called from the allocators
+//
+#[inline]
+pub(crate) fn record_alloc() { ALLOCS.add(1); }
+
+#[inline]
+pub(crate) fn record_dealloc() { FREES.add(1); }
@@ -9,6 +9,20 @@
  *    Implements a wrapper allocator that tracks / logs all memory
  *    operations.
  *
+ *    What gets recorded is decided by a `FilterPolicy` (see `set_filter`).
+ *    Size rules are cheap; call-site rules resolve each new instruction
+ *    address once and cache the verdict.
+ *
+ *    The allocator counters (regions, watermarks, ...) see every block, the
+ *    tracker's own bookkeeping included, so allocs and frees always pair
+ *    up. No_alloc sections and the chrome trace only see the caller's
+ *    blocks.
+ *
+ *    Every allocation seen gets the next sequence number (kept or not, so
+ *    ids don't shift with the filter); see `breakpoint` for stopping on
+ *    a given one, and `watch` for logging everything that happens at an
+ *    address.
+ *
  *    Idea started from the following blog post:
  *     - https://shiver.github.io/post/tracking-heap-allocations-in-rust/
  *
@@ -20,14 +34,38 @@ use std::{
         Layout,
     },
     cell::RefCell,
-    sync::Mutex,
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    sync::{
+        Mutex,
+        TryLockError,
+    },
+    time::Duration,
 };
 
 pub use super::{
+    dump::Snapshot,
     DefaultTracker,
+    Tee,
     Tracker,
 };
+use super::{
+    autodump::MemoryReport,
+    breakpoint,
+    filter::FilterPolicy,
+    hooks,
+    tee,
+    watch::{
+        self,
+        Touch,
+    },
+};
+
 
+// How long (in 1ms steps) a report waits for the tracker lock
+const LOCK_ATTEMPTS: usize = 100;
 
 thread_entry_guard!(TRACING_GUARD);
 
@@ -37,26 +75,21 @@ where
     A: GlobalAlloc,
     T: Tracker,
 {
-    inner:      A,
-    tracker:    Mutex<RefCell<T>>,
-    filter_std: bool,
+    inner: A,
+    state: Mutex<RefCell<State<T>>>,
 }
 
 impl Tracing<std::alloc::System, DefaultTracker> {
-    pub const fn default() -> Self {
-        Self {
-            inner:      std::alloc::System,
-            tracker:    Mutex::new(RefCell::new(DefaultTracker::new())),
-            filter_std: true,
-        }
-    }
+    pub const fn default() -> Self { Self::new(std::alloc::System, DefaultTracker::new(), true) }
 
-    pub const fn default_with_std() -> Self {
-        Self {
-            inner:      std::alloc::System,
-            tracker:    Mutex::new(RefCell::new(DefaultTracker::new())),
-            filter_std: false,
-        }
+    pub const fn default_with_std() -> Self { Self::new(std::alloc::System, DefaultTracker::new(), false) }
+}
+
+impl Tracing<std::alloc::System, Tee> {
+    /// Trackers picked at runtime from `SL_ALLOC_TRACKERS` (see `Tee` for
+    /// the names), defaulting to the default tracker.
+    pub const fn from_env() -> Self {
+        Self::new(std::alloc::System, Tee::from_env_system("SL_ALLOC_TRACKERS", "default"), true)
     }
 }
 
@@ -66,22 +99,125 @@ where
     T: Tracker,
 {
     pub const fn new(inner: A, tracker: T, filter_std: bool) -> Self {
+        let filter = match filter_std {
+            | true => FilterPolicy::new(),
+            | false => FilterPolicy::with_std(),
+        };
+
         Self {
             inner,
-            tracker: Mutex::new(RefCell::new(tracker)),
-            filter_std,
+            state: Mutex::new(RefCell::new(State {
+                tracker,
+                filter,
+                verdicts: BTreeMap::new(),
+                skipped: BTreeSet::new(),
+                next_id: 0,
+            })),
         }
     }
 
-    pub fn dump_info<Writer: std::io::Write + ?Sized>(&self, out: &mut Writer) {
+    /// Replaces the filter policy. Best set before the allocations of
+    /// interest; blocks recorded under the old size rules may otherwise
+    /// show up as leaks or unknown frees.
+    pub fn set_filter(&self, filter: FilterPolicy) {
         no_reentry_per_thread!(TRACING_GUARD, {
-            let tracker_guard = self.tracker.lock().expect("unable to unwrap tracker");
-            (*tracker_guard)
-                .borrow_mut()
-                .dump_info(out, self.filter_std)
-                .expect("failed to write tracker data");
+            let state_guard = self.state.lock().expect("unable to unwrap tracker");
+            let mut state = (*state_guard).borrow_mut();
+            state.tracker.set_filter(&filter);
+            state.verdicts.clear();
+            state.filter = filter;
         });
     }
+
+    /// Swaps in a new tracker (e.g. a `Tee` built from the program's own
+    /// configuration). Whatever the old one recorded is dropped.
+    pub fn set_tracker(&self, tracker: T) {
+        no_reentry_per_thread!(TRACING_GUARD, {
+            let state_guard = self.state.lock().expect("unable to unwrap tracker");
+            let mut state = (*state_guard).borrow_mut();
+            let state = &mut *state;
+            state.tracker = tracker;
+            state.tracker.set_filter(&state.filter);
+        });
+    }
+
+    pub fn dump_info(&self, out: &mut dyn std::io::Write) {
+        self.try_dump_info(out).expect("failed to write tracker data");
+    }
+
+    // Also used from panic hooks (see `MemoryReport`), which may run on a
+    // thread already inside the allocator or while another thread holds
+    // the lock for good. Both are noted in the report instead of blocking,
+    // and a poisoned lock is returned as an error instead of panicking.
+    fn try_dump_info(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let mut res = None;
+
+        no_reentry_per_thread!(TRACING_GUARD, {
+            res = Some(self.try_dump_locked(out));
+        });
+
+        res.unwrap_or_else(|| writeln!(out, "tracker busy: reentrant call from inside the tracing allocator"))
+    }
+
+    fn try_dump_locked(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let mut attempts = 0;
+        let state_guard = loop {
+            match self.state.try_lock() {
+                | Ok(state_guard) => break state_guard,
+                | Err(TryLockError::Poisoned(_)) => return Err(std::io::Error::other("tracker lock poisoned")),
+                | Err(TryLockError::WouldBlock) if attempts < LOCK_ATTEMPTS => {
+                    attempts += 1;
+                    std::thread::sleep(Duration::from_millis(1));
+                },
+                | Err(TryLockError::WouldBlock) => {
+                    return writeln!(out, "tracker busy: lock held by another thread");
+                },
+            }
+        };
+
+        let state = &mut *(*state_guard).borrow_mut();
+        state.tracker.dump_info(out, &state.filter)
+    }
+
+    /// Captures the current live allocation set. Memory backing the
+    /// snapshot itself is allocated untracked.
+    pub fn snapshot(&self, name: &str) -> Snapshot {
+        let mut snap = None;
+
+        no_reentry_per_thread!(TRACING_GUARD, {
+            let state_guard = self.state.lock().expect("unable to unwrap tracker");
+            let state = &mut *(*state_guard).borrow_mut();
+            snap = Some(state.tracker.snapshot(name, &state.filter));
+        });
+
+        snap.unwrap_or_else(|| Snapshot::new(name))
+    }
+
+    // Called without the state lock held; reporting a hit captures a
+    // stack and logs. Failed allocations never touch memory.
+    fn watch(&self, touch: Touch) {
+        tee::log_bad_spec();
+
+        if matches!(touch, Touch::Alloc { ptr: 0, .. }) {
+            return;
+        }
+
+        let hits = watch::hits(&touch);
+        if hits.is_empty() {
+            return;
+        }
+
+        let filter = self.state.lock().expect("unable to unwrap tracker").borrow().filter.clone();
+        watch::report(touch, &hits, &filter);
+    }
+}
+
+impl<A, T> MemoryReport for Tracing<A, T>
+where
+    A: GlobalAlloc + Sync,
+    T: Tracker + Send,
+{
+    fn write_report(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> { self.try_dump_info(out) }
 }
 
 unsafe impl<A, T> GlobalAlloc for Tracing<A, T>
@@ -93,9 +229,53 @@ where
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let ptr = self.inner.alloc(layout);
 
+        hooks::record_alloc(layout);
+        no_reentry_per_thread!(TRACING_GUARD, {
+            hooks::observe_alloc(layout);
+            let id = {
+                let state_guard = self.state.lock().expect("unable to unwrap tracker");
+                let mut state = (*state_guard).borrow_mut();
+                let id = state.next_id();
+                if state.keeps(ptr, layout.size()) {
+                    state.tracker.track_alloc(id, ptr, layout);
+                }
+                id
+            };
+            self.watch(Touch::Alloc {
+                id,
+                ptr: ptr as usize,
+                size: layout.size(),
+                zeroed: false,
+            });
+            breakpoint::check(id, layout);
+        });
+
+        ptr
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+
+        hooks::record_alloc(layout);
         no_reentry_per_thread!(TRACING_GUARD, {
-            let tracker_guard = self.tracker.lock().expect("unable to unwrap tracker");
-            (*tracker_guard).borrow_mut().track_alloc(ptr, layout);
+            hooks::observe_alloc(layout);
+            let id = {
+                let state_guard = self.state.lock().expect("unable to unwrap tracker");
+                let mut state = (*state_guard).borrow_mut();
+                let id = state.next_id();
+                if state.keeps(ptr, layout.size()) {
+                    state.tracker.track_alloc_zeroed(id, ptr, layout);
+                }
+                id
+            };
+            self.watch(Touch::Alloc {
+                id,
+                ptr: ptr as usize,
+                size: layout.size(),
+                zeroed: true,
+            });
+            breakpoint::check(id, layout);
         });
 
         ptr
@@ -105,9 +285,134 @@ where
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         self.inner.dealloc(ptr, layout);
 
+        hooks::record_dealloc(layout);
         no_reentry_per_thread!(TRACING_GUARD, {
-            let tracker_guard = self.tracker.lock().expect("unable to unwrap tracker");
-            (*tracker_guard).borrow_mut().track_dealloc(ptr, layout);
+            hooks::observe_dealloc(layout);
+            {
+                let state_guard = self.state.lock().expect("unable to unwrap tracker");
+                let mut state = (*state_guard).borrow_mut();
+                if state.kept(ptr, layout.size()) {
+                    state.tracker.track_dealloc(ptr, layout);
+                }
+            }
+            self.watch(Touch::Free {
+                ptr:  ptr as usize,
+                size: layout.size(),
+            });
+        });
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+
+        // On failure the original block is left untouched.
+        if new_ptr.is_null() {
+            return new_ptr;
+        }
+
+        // SAFETY: the caller guarantees `new_size` is valid for the
+        // original alignment.
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        hooks::record_realloc(layout, new_layout);
+        no_reentry_per_thread!(TRACING_GUARD, {
+            hooks::observe_realloc(layout, new_layout);
+            let id = {
+                let state_guard = self.state.lock().expect("unable to unwrap tracker");
+                let mut state = (*state_guard).borrow_mut();
+                let id = state.next_id();
+
+                // The filter may keep the block at one size but not the other.
+                match (state.kept(ptr, layout.size()), state.keeps(new_ptr, new_size)) {
+                    | (true, true) => state.tracker.track_realloc(id, ptr, new_ptr, layout, new_layout),
+                    | (true, false) => state.tracker.track_dealloc(ptr, layout),
+                    | (false, true) => state.tracker.track_alloc(id, new_ptr, new_layout),
+                    | (false, false) => {},
+                }
+                id
+            };
+            self.watch(Touch::Realloc {
+                id,
+                old_ptr: ptr as usize,
+                old_size: layout.size(),
+                new_ptr: new_ptr as usize,
+                new_size,
+            });
+            breakpoint::check(id, new_layout);
+        });
+
+        new_ptr
+    }
+}
+
+
+//
+// Everything guarded by the allocator's lock
+//
+struct State<T> {
+    tracker:  T,
+    filter:   FilterPolicy,
+    // Instruction address => (included, excluded) under the call-site rules
+    verdicts: BTreeMap<usize, (bool, bool)>,
+    // Live blocks dropped by the call-site rules
+    skipped:  BTreeSet<usize>,
+    // Sequence number of the next allocation
+    next_id:  usize,
+}
+
+impl<T: Tracker> State<T> {
+    fn next_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Decides whether a new block is recorded.
+    fn keeps(&mut self, ptr: *mut u8, size: usize) -> bool {
+        if !self.filter.keeps_size(size) {
+            return false;
+        }
+
+        if !self.site_allowed() {
+            self.skipped.insert(ptr as usize);
+            return false;
+        }
+
+        true
+    }
+
+    /// Whether a block being released was recorded when it was created.
+    fn kept(&mut self, ptr: *mut u8, size: usize) -> bool {
+        self.filter.keeps_size(size) && !self.skipped.remove(&(ptr as usize))
+    }
+
+    fn site_allowed(&mut self) -> bool {
+        if !self.filter.has_site_rules() {
+            return true;
+        }
+
+        let (filter, verdicts) = (&self.filter, &mut self.verdicts);
+        let (mut included, mut excluded) = (filter.include.is_empty(), false);
+
+        backtrace::trace(|frame| {
+            let (inc, exc) = *verdicts.entry(frame.ip() as usize).or_insert_with(|| {
+                let mut verdict = (false, false);
+                backtrace::resolve_frame(frame, |sym| {
+                    if let Some(name) = sym.name() {
+                        let (inc, exc) = filter.match_symbol(&name.to_string());
+                        verdict.0 |= inc;
+                        verdict.1 |= exc;
+                    }
+                });
+                verdict
+            });
+
+            included |= inc;
+            excluded |= exc;
+            !excluded
         });
+
+        included && !excluded
     }
 }
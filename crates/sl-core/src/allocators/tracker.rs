@@ -13,27 +13,113 @@
 
 use std::{
     alloc::Layout,
-    collections::{
-        HashMap,
-        HashSet,
+    collections::HashMap,
+};
+
+use super::{
+    dump::{
+        self,
+        Event,
+        Report,
+        Snapshot,
+        Symbol,
+    },
+    filter::FilterPolicy,
+    region,
+    stacks::{
+        self,
+        StackCapture,
+        StackId,
+        StackTable,
     },
 };
 
-use backtrace::Backtrace;
+
+// Frames between the allocation site and the tracker (allocator, std
+// alloc shims, tracker internals). Added on top of the filter's depth when
+// capturing so the frames that end up in reports are not cut short.
+pub(super) const ALLOC_FRAMES: usize = 16;
 
 
 //
-// Tracker trait for overriding default memory tracking behaviors
+// Tracker trait for overriding default memory tracking behaviors. Object
+// safe, so trackers can be boxed and picked at runtime (see `Tee`).
 //
 pub trait Tracker {
-    fn dump_info<Writer: std::io::Write + ?Sized>(
+    fn dump_info(
         &mut self,
-        out: &mut Writer,
-        filter_std: bool,
+        out: &mut dyn std::io::Write,
+        filter: &FilterPolicy,
     ) -> std::io::Result<()>;
 
-    fn track_alloc(&mut self, ptr: *mut u8, layout: Layout);
+    // `id` is the allocation's sequence number: every allocation (and
+    // reallocation) the allocator sees gets the next one, filtered or not.
+    fn track_alloc(&mut self, id: usize, ptr: *mut u8, layout: Layout);
     fn track_dealloc(&mut self, ptr: *mut u8, layout: Layout);
+
+    // Trackers that don't care how a block came to be can ignore these;
+    // by default they look like a plain alloc / free + alloc.
+    fn track_alloc_zeroed(&mut self, id: usize, ptr: *mut u8, layout: Layout) { self.track_alloc(id, ptr, layout) }
+
+    fn track_realloc(
+        &mut self,
+        id: usize,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) {
+        self.track_dealloc(old_ptr, old_layout);
+        self.track_alloc(id, new_ptr, new_layout);
+    }
+
+    // Trackers that don't keep per-allocation state have nothing to show.
+    fn snapshot(&mut self, name: &str, _filter: &FilterPolicy) -> Snapshot { Snapshot::new(name) }
+
+    // Called whenever the allocator's filter changes, for trackers that
+    // can apply parts of it (e.g. stack depth) while capturing.
+    fn set_filter(&mut self, _filter: &FilterPolicy) {}
+}
+
+impl<T: Tracker + ?Sized> Tracker for Box<T> {
+    fn dump_info(&mut self, out: &mut dyn std::io::Write, filter: &FilterPolicy) -> std::io::Result<()> {
+        (**self).dump_info(out, filter)
+    }
+
+    fn track_alloc(&mut self, id: usize, ptr: *mut u8, layout: Layout) { (**self).track_alloc(id, ptr, layout) }
+
+    fn track_dealloc(&mut self, ptr: *mut u8, layout: Layout) { (**self).track_dealloc(ptr, layout) }
+
+    fn track_alloc_zeroed(&mut self, id: usize, ptr: *mut u8, layout: Layout) {
+        (**self).track_alloc_zeroed(id, ptr, layout)
+    }
+
+    fn track_realloc(
+        &mut self,
+        id: usize,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) {
+        (**self).track_realloc(id, old_ptr, new_ptr, old_layout, new_layout)
+    }
+
+    fn snapshot(&mut self, name: &str, filter: &FilterPolicy) -> Snapshot { (**self).snapshot(name, filter) }
+
+    fn set_filter(&mut self, filter: &FilterPolicy) { (**self).set_filter(filter) }
+}
+
+
+//
+// How the default tracker writes out its log
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpMode {
+    // Symbols are resolved in-process (needs debug info at runtime)
+    Resolved,
+    // Raw instruction addresses + module map for offline symbolization
+    Raw,
 }
 
 
@@ -41,79 +127,274 @@ pub trait Tracker {
 // Default tracker tracked objects
 //
 enum Tracked {
-    Allocation(usize, Layout, Backtrace),
+    Allocation {
+        id:     usize,
+        ptr:    usize,
+        layout: Layout,
+        zeroed: bool,
+        region: usize,
+        stack:  StackId,
+    },
+    Reallocation {
+        id:         usize,
+        old_ptr:    usize,
+        new_ptr:    usize,
+        old_layout: Layout,
+        new_layout: Layout,
+        region:     usize,
+        stack:      StackId,
+    },
     Deallocation(usize),
 }
 
+impl Tracked {
+    fn stack(&self) -> Option<StackId> {
+        match self {
+            | Tracked::Allocation { stack, .. } | Tracked::Reallocation { stack, .. } => Some(*stack),
+            | Tracked::Deallocation(_) => None,
+        }
+    }
+
+    // Instruction addresses are only needed for raw dumps.
+    fn event(&self, stacks: &StackTable, with_ips: bool) -> Event {
+        let ips = |stack: &StackId| match with_ips {
+            | true => stacks.get(*stack).iter().map(|ip| *ip as u64).collect(),
+            | false => Vec::new(),
+        };
+
+        match self {
+            | Tracked::Allocation {
+                id,
+                ptr,
+                layout,
+                zeroed,
+                region,
+                stack,
+            } => Event::Alloc {
+                id: *id,
+                ptr: *ptr,
+                size: layout.size(),
+                align: layout.align(),
+                zeroed: *zeroed,
+                region: region::name_of(*region).to_string(),
+                ips: ips(stack),
+            },
+            | Tracked::Reallocation {
+                id,
+                old_ptr,
+                new_ptr,
+                old_layout,
+                new_layout,
+                region,
+                stack,
+            } => Event::Realloc {
+                id: *id,
+                old_ptr: *old_ptr,
+                new_ptr: *new_ptr,
+                old_size: old_layout.size(),
+                new_size: new_layout.size(),
+                align: new_layout.align(),
+                region: region::name_of(*region).to_string(),
+                ips: ips(stack),
+            },
+            | Tracked::Deallocation(ptr) => Event::Free { ptr: *ptr },
+        }
+    }
+}
+
 
 //
 // "default" Tracker implementation
 //
 pub struct DefaultTracker {
-    tracked: Vec<Tracked>,
+    tracked:    Vec<Tracked>,
+    stacks:     StackTable,
+    mode:       DumpMode,
+    capture:    StackCapture,
+    // Raw frames captured: the filter's needs, capped by `max_frames`
+    depth:      usize,
+    max_frames: usize,
 }
 
 
 impl DefaultTracker {
-    pub const fn new() -> Self {
+    pub const fn new() -> Self { Self::with_mode(DumpMode::Resolved) }
+
+    pub const fn raw() -> Self { Self::with_mode(DumpMode::Raw) }
+
+    pub const fn with_mode(mode: DumpMode) -> Self {
         Self {
             tracked: Vec::new(),
+            stacks: StackTable::new(),
+            mode,
+            capture: StackCapture::Unwind,
+            depth: usize::MAX,
+            max_frames: usize::MAX,
         }
     }
+
+    /// Picks how stacks are captured and caps how many raw frames are kept
+    /// per stack (allocator frames included).
+    ///
+    /// # Safety
+    /// With `StackCapture::FramePointers` the walker dereferences whatever
+    /// the frame pointer chain holds. Every frame on every allocating
+    /// thread's stack must keep its frame pointer (the binary, its
+    /// dependencies and std, see `stacks`); otherwise it may read arbitrary
+    /// memory. `StackCapture::Unwind` has no requirements.
+    pub const unsafe fn with_capture(mut self, capture: StackCapture, max_frames: usize) -> Self {
+        self.capture = capture;
+        self.max_frames = max_frames;
+        self.depth = max_frames;
+        self
+    }
+
+    /// Number of unique stacks captured so far.
+    pub fn unique_stacks(&self) -> usize { self.stacks.len() }
+
+    fn capture_stack(&mut self) -> StackId { self.stacks.capture(self.capture, self.depth) }
+
+    fn dump_resolved<Writer: std::io::Write + ?Sized>(
+        &mut self,
+        out: &mut Writer,
+        filter: &FilterPolicy,
+    ) -> std::io::Result<()> {
+        let mut report = Report::new(filter);
+        let mut symbols = Symbols::new(&self.stacks);
+
+        for e in self.tracked.iter() {
+            let resolved = e.stack().map(|stack| symbols.get(stack)).unwrap_or_default();
+            report.record(out, &e.event(&self.stacks, false), resolved)?;
+        }
+
+        report.finish(out)
+    }
+
+    fn dump_raw<Writer: std::io::Write + ?Sized>(&mut self, out: &mut Writer) -> std::io::Result<()> {
+        dump::write_raw_header(out)?;
+        for module in dump::loaded_modules().iter() {
+            dump::write_raw_module(out, module)?;
+        }
+
+        for e in self.tracked.iter() {
+            e.event(&self.stacks, true).write_raw(out)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Default for DefaultTracker {
+    fn default() -> Self { Self::new() }
 }
 
 
 impl Tracker for DefaultTracker {
-    fn dump_info<Writer: std::io::Write + ?Sized>(
+    fn dump_info(
         &mut self,
-        out: &mut Writer,
-        filter_std: bool,
+        out: &mut dyn std::io::Write,
+        filter: &FilterPolicy,
     ) -> std::io::Result<()> {
-        let mut leaked: HashMap<usize, (usize, usize)> = HashMap::new();
-        let mut unknown_frees: HashSet<usize> = HashSet::new();
+        match self.mode {
+            | DumpMode::Resolved => self.dump_resolved(out, filter),
+            | DumpMode::Raw => self.dump_raw(out),
+        }
+    }
 
-        for (idx, e) in self.tracked.iter_mut().enumerate() {
+    fn snapshot(&mut self, name: &str, filter: &FilterPolicy) -> Snapshot {
+        let mut live: HashMap<usize, usize> = HashMap::new();
+        for (idx, e) in self.tracked.iter().enumerate() {
             match e {
-                | Tracked::Allocation(ptr, layout, bt) => {
-                    trace_allocation(out, idx, *ptr, layout, bt, filter_std)?;
-                    leaked.insert(*ptr, (idx, layout.size()));
+                | Tracked::Allocation { ptr, .. } => {
+                    live.insert(*ptr, idx);
+                },
+                | Tracked::Reallocation { old_ptr, new_ptr, .. } => {
+                    live.remove(old_ptr);
+                    live.insert(*new_ptr, idx);
                 },
                 | Tracked::Deallocation(ptr) => {
-                    if leaked.remove(ptr).is_none() {
-                        unknown_frees.insert(*ptr);
-                    }
+                    live.remove(ptr);
                 },
             }
         }
 
-        if leaked.len() > 0 {
-            writeln!(out, "\n\n=============== POSSIBLE LEAKS ===============")?;
-            for (k, v) in leaked.iter() {
-                writeln!(
-                    out,
-                    "[ID: {}] => {} bytes @ address {:p}",
-                    v.0, v.1, *k as *const u8
-                )?;
+        let mut ids: Vec<usize> = live.into_values().collect();
+        ids.sort_unstable();
+
+        let mut snap = Snapshot::new(name);
+        let mut symbols = Symbols::new(&self.stacks);
+        for idx in ids {
+            let (id, ptr, size, stack) = match &self.tracked[idx] {
+                | Tracked::Allocation {
+                    id, ptr, layout, stack, ..
+                } => (*id, *ptr, layout.size(), *stack),
+                | Tracked::Reallocation {
+                    id,
+                    new_ptr,
+                    new_layout,
+                    stack,
+                    ..
+                } => (*id, *new_ptr, new_layout.size(), *stack),
+                | Tracked::Deallocation(_) => continue,
+            };
+
+            if !filter.keeps_size(size) {
+                continue;
             }
-        }
 
-        if unknown_frees.len() > 0 {
-            writeln!(out, "\n\n=============== UNKNOWN FREES ===============")?;
-            for p in unknown_frees.iter() {
-                writeln!(out, "  @ Address {:p}", *p as *const u8)?;
+            if let Some(frames) = filter.frames(symbols.get(stack)) {
+                snap.add(id, ptr, size, frames);
             }
         }
 
+        snap
+    }
 
-        Ok(())
+    fn set_filter(&mut self, filter: &FilterPolicy) { self.depth = capture_depth(filter).min(self.max_frames); }
+
+    fn track_alloc(&mut self, id: usize, ptr: *mut u8, layout: Layout) {
+        let stack = self.capture_stack();
+        self.tracked.push(Tracked::Allocation {
+            id,
+            ptr: ptr as usize,
+            layout,
+            zeroed: false,
+            region: region::current(),
+            stack,
+        });
     }
 
-    fn track_alloc(&mut self, ptr: *mut u8, layout: Layout) {
-        self.tracked.push(Tracked::Allocation(
-            ptr as usize,
+    fn track_alloc_zeroed(&mut self, id: usize, ptr: *mut u8, layout: Layout) {
+        let stack = self.capture_stack();
+        self.tracked.push(Tracked::Allocation {
+            id,
+            ptr: ptr as usize,
             layout,
-            Backtrace::new_unresolved(),
-        ));
+            zeroed: true,
+            region: region::current(),
+            stack,
+        });
+    }
+
+    fn track_realloc(
+        &mut self,
+        id: usize,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) {
+        let stack = self.capture_stack();
+        self.tracked.push(Tracked::Reallocation {
+            id,
+            old_ptr: old_ptr as usize,
+            new_ptr: new_ptr as usize,
+            old_layout,
+            new_layout,
+            region: region::current(),
+            stack,
+        });
     }
 
     fn track_dealloc(&mut self, ptr: *mut u8, _layout: Layout) {
@@ -122,65 +403,28 @@ impl Tracker for DefaultTracker {
 }
 
 
-#[inline]
-fn trace_allocation<Writer: std::io::Write + ?Sized>(
-    out: &mut Writer,
-    index: usize,
-    ptr: usize,
-    layout: &Layout,
-    bt: &mut Backtrace,
-    filter_std: bool,
-) -> std::io::Result<()> {
-    writeln!(
-        out,
-        "[ID: {}] ---------- Allocated {} bytes @ Address {:p} ----------",
-        index,
-        layout.size(),
-        ptr as *const u8,
-    )?;
-
-    // These backtraces were not originally resolved.
-    bt.resolve();
-
-    for frame in bt.frames().iter() {
-        for sym in frame.symbols() {
-            if filter_std {
-                let fname = sym
-                    .filename()
-                    .map(|f| f.display().to_string())
-                    .unwrap_or_default();
-                if fname.starts_with("/rustc") {
-                    continue;
-                }
-            }
-
-            let sym_name = sym
-                .name()
-                .map(|n| n.to_string())
-                .unwrap_or(UNKNOWN.to_string());
-
-            if IGNORED_SYMBOLS
-                .iter()
-                .any(|sym| sym_name.starts_with(sym) || sym_name.ends_with(sym))
-            {
-                continue;
-            }
+/// Raw frames worth capturing for `filter`.
+pub(super) fn capture_depth(filter: &FilterPolicy) -> usize { filter.max_depth.saturating_add(ALLOC_FRAMES) }
 
-            let line_number = sym.lineno().unwrap_or(u32::MAX);
+//
+// Symbols of the interned stacks, resolved once per stack for a report
+//
+struct Symbols<'a> {
+    stacks:   &'a StackTable,
+    resolved: HashMap<StackId, Vec<Symbol>>,
+}
 
-            writeln!(out, "   > {sym_name} @ line {line_number}")?;
+impl<'a> Symbols<'a> {
+    fn new(stacks: &'a StackTable) -> Self {
+        Self {
+            stacks,
+            resolved: HashMap::new(),
         }
     }
 
-    write!(out, "\n\n")?;
-    Ok(())
+    fn get(&mut self, stack: StackId) -> &[Symbol] {
+        self.resolved
+            .entry(stack)
+            .or_insert_with(|| stacks::resolve_ips(self.stacks.get(stack)))
+    }
 }
-
-
-const UNKNOWN: &str = "<unknown>";
-const IGNORED_SYMBOLS: [&str; 4] = [
-    "_main",
-    "__rg_alloc",
-    "backtrace::",
-    "<sl_core::allocators::",
-];
@@ -0,0 +1,182 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/watch
+ *
+ * Purpose:
+ *    Address watchpoints for the `Tracing` allocator. Every allocation,
+ *    reallocation and free whose block overlaps a watched range is logged
+ *    (`log::warn!`) on the spot with its stack, so chasing an "unknown
+ *    free" or a corrupted block starts from the full history of the
+ *    address instead of one summary line.
+ *
+ *    Watches apply regardless of the allocator's filter policy, except that
+ *    the logged stacks follow its frame rules.
+ *
+ */
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
+use super::{
+    filter::FilterPolicy,
+    stacks,
+    tracker,
+};
+
+
+static WATCHES: Mutex<Vec<Watch>> = Mutex::new(Vec::new());
+// Number of registered watches; lets the allocator skip the lock when 0
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WatchId(usize);
+
+struct Watch {
+    id:    WatchId,
+    start: usize,
+    end:   usize,
+}
+
+
+/// Watches every block containing `addr`.
+pub fn watch_address(addr: usize) -> WatchId { watch_range(addr, 1) }
+
+/// Watches every block overlapping `[addr, addr + len)`.
+pub fn watch_range(addr: usize, len: usize) -> WatchId {
+    let id = WatchId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    let mut watches = WATCHES.lock().expect("unable to lock watches");
+    watches.push(Watch {
+        id,
+        start: addr,
+        end: addr.saturating_add(len.max(1)),
+    });
+    ACTIVE.store(watches.len(), Ordering::Release);
+
+    id
+}
+
+/// Removes a watch. Returns false if it was already removed.
+pub fn unwatch(id: WatchId) -> bool {
+    let mut watches = WATCHES.lock().expect("unable to lock watches");
+    let before = watches.len();
+    watches.retain(|w| w.id != id);
+    ACTIVE.store(watches.len(), Ordering::Release);
+    watches.len() != before
+}
+
+
+//
+// Memory operations as seen by the allocator
+//
+pub(crate) enum Touch {
+    Alloc {
+        id:     usize,
+        ptr:    usize,
+        size:   usize,
+        zeroed: bool,
+    },
+    Realloc {
+        id:       usize,
+        old_ptr:  usize,
+        old_size: usize,
+        new_ptr:  usize,
+        new_size: usize,
+    },
+    Free {
+        ptr:  usize,
+        size: usize,
+    },
+}
+
+impl Touch {
+    fn overlaps(&self, watch: &Watch) -> bool {
+        let hits = |ptr: usize, size: usize| ptr < watch.end && watch.start < ptr.saturating_add(size.max(1));
+
+        match *self {
+            | Touch::Alloc { ptr, size, .. } | Touch::Free { ptr, size } => hits(ptr, size),
+            | Touch::Realloc {
+                old_ptr,
+                old_size,
+                new_ptr,
+                new_size,
+                ..
+            } => hits(old_ptr, old_size) || hits(new_ptr, new_size),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            | Touch::Alloc { id, ptr, size, zeroed } => format!(
+                "{} {} bytes @ {:p} [ID: {}]",
+                if zeroed { "zalloc" } else { "alloc" },
+                size,
+                ptr as *const u8,
+                id
+            ),
+            | Touch::Realloc {
+                id,
+                old_ptr,
+                old_size,
+                new_ptr,
+                new_size,
+            } => format!(
+                "realloc {} => {} bytes @ {:p} => {:p} [ID: {}]",
+                old_size, new_size, old_ptr as *const u8, new_ptr as *const u8, id
+            ),
+            | Touch::Free { ptr, size } => format!("free {} bytes @ {:p}", size, ptr as *const u8),
+        }
+    }
+}
+
+
+//
+// Called from the allocator for every operation, outside of its lock (the
+// watch list allocates while locked, and reports log)
+//
+
+/// Ids of the watches an operation overlaps.
+#[inline]
+pub(crate) fn hits(touch: &Touch) -> Vec<usize> {
+    if ACTIVE.load(Ordering::Acquire) == 0 {
+        return Vec::new();
+    }
+
+    WATCHES
+        .lock()
+        .expect("unable to lock watches")
+        .iter()
+        .filter(|w| touch.overlaps(w))
+        .map(|w| w.id.0)
+        .collect()
+}
+
+/// Logs an operation that hit watches, with the current stack.
+pub(crate) fn report(touch: Touch, hits: &[usize], filter: &FilterPolicy) {
+    let mut ips = Vec::new();
+    let depth = tracker::capture_depth(filter);
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as usize);
+        ips.len() < depth
+    });
+
+    let mut msg = format!("watch {:?}: {}", hits, touch.describe());
+    for frame in filter.format_frames(&stacks::resolve_ips(&ips)) {
+        let _ = write!(msg, "\n   > {frame}");
+    }
+
+    log::warn!("{}", msg);
+}
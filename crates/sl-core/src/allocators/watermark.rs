@@ -0,0 +1,162 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_core
+ * Module:  allocators/watermark
+ *
+ * Purpose:
+ *    Live byte watermarks. The allocators keep a process-wide count of
+ *    live (requested) bytes; a helper thread samples it and calls the
+ *    registered callbacks when it crosses a watermark. Callbacks run on the
+ *    helper thread, so they are free to allocate (shed caches, write a
+ *    heap report, ...).
+ *
+ *    Each watermark fires once on the way up (live >= bytes) and again on
+ *    the way down once live bytes drop below `bytes - hysteresis`, so a
+ *    count hovering around the mark doesn't flap. Spikes shorter than the
+ *    sampling interval can be missed.
+ *
+ */
+
+use std::{
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        Once,
+    },
+    time::Duration,
+};
+
+use super::{
+    Counter,
+    ShardedCounter,
+};
+
+
+const DEFAULT_INTERVAL_MS: u64 = 10;
+
+static LIVE: ShardedCounter = ShardedCounter::ZERO;
+static WATERMARKS: Mutex<Vec<Watermark>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL_MS);
+static HELPER: Once = Once::new();
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Crossing {
+    // Live bytes reached the watermark
+    Above,
+    // Live bytes fell back below the watermark minus its hysteresis
+    Below,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WatermarkId(usize);
+
+type Callback = Arc<dyn Fn(Crossing, usize) + Send + Sync>;
+
+struct Watermark {
+    id:         WatermarkId,
+    bytes:      usize,
+    hysteresis: usize,
+    above:      bool,
+    callback:   Callback,
+}
+
+
+/// Bytes currently allocated through the sl_core allocators.
+pub fn live_bytes() -> usize { LIVE.get() }
+
+/// Calls `callback` (with the direction and the live byte count) on the
+/// watermark helper thread whenever live bytes cross `bytes`.
+pub fn on_watermark<F>(bytes: usize, hysteresis: usize, callback: F) -> WatermarkId
+where
+    F: Fn(Crossing, usize) + Send + Sync + 'static,
+{
+    let id = WatermarkId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    WATERMARKS
+        .lock()
+        .expect("unable to lock watermarks")
+        .push(Watermark {
+            id,
+            bytes,
+            hysteresis: hysteresis.min(bytes),
+            above: false,
+            callback: Arc::new(callback),
+        });
+
+    HELPER.call_once(|| {
+        std::thread::Builder::new()
+            .name("sl-watermarks".to_string())
+            .spawn(watch)
+            .expect("failed to spawn watermark thread");
+    });
+
+    id
+}
+
+/// Unregisters a watermark. Returns false if it was already removed.
+pub fn remove_watermark(id: WatermarkId) -> bool {
+    let mut marks = WATERMARKS.lock().expect("unable to lock watermarks");
+    let before = marks.len();
+    marks.retain(|m| m.id != id);
+    marks.len() != before
+}
+
+/// Sets how often the helper thread samples live bytes.
+pub fn set_watermark_interval(interval: Duration) {
+    INTERVAL_MS.store(interval.as_millis().max(1) as u64, Ordering::Relaxed);
+}
+
+
+fn watch() {
+    let mut fired: Vec<(Callback, Crossing)> = Vec::new();
+
+    loop {
+        std::thread::sleep(Duration::from_millis(INTERVAL_MS.load(Ordering::Relaxed)));
+
+        let live = live_bytes();
+        {
+            let mut marks = WATERMARKS.lock().expect("unable to lock watermarks");
+            for m in marks.iter_mut() {
+                if !m.above && live >= m.bytes {
+                    m.above = true;
+                    fired.push((m.callback.clone(), Crossing::Above));
+                } else if m.above && live < m.bytes - m.hysteresis {
+                    m.above = false;
+                    fired.push((m.callback.clone(), Crossing::Below));
+                }
+            }
+        }
+
+        // Outside the lock; callbacks may register or remove watermarks. A
+        // panicking callback must not take the helper thread (and with it
+        // every other watermark) down; it's never restarted.
+        for (callback, crossing) in fired.drain(..) {
+            if panic::catch_unwind(AssertUnwindSafe(|| callback(crossing, live))).is_err() {
+                log::error!("watermark callback panicked ({:?} at {} live bytes)", crossing, live);
+            }
+        }
+    }
+}
+
+
+//
+// Hooks called from the allocators
+//
+#[inline]
+pub(crate) fn record_alloc(size: usize) { LIVE.add(size); }
+
+#[inline]
+pub(crate) fn record_dealloc(size: usize) { LIVE.sub(size); }
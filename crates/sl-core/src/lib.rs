@@ -6,6 +6,9 @@
  *
  */
 
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "alloc-api-nightly", feature(allocator_api))]
+
 #[macro_use]
 pub mod macros;
 
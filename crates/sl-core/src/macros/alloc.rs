@@ -12,13 +12,65 @@
  */
 
 
+cfg_alloc_any! {
+    cfg_std! {
+        #[macro_export]
+        macro_rules! alloc_region {
+            ( $name:literal, $body:block ) => {
+                {
+                    static SL_REGION: $crate::allocators::Region = $crate::allocators::Region::new($name);
+                    let _sl_rg = SL_REGION.enter();
+                    $body
+                }
+            };
+        }
+
+        // Chrome trace slice for `trace_block!` / `trace_fn!`; a no-op
+        // without `std`.
+        #[doc(hidden)]
+        #[macro_export]
+        macro_rules! __sl_chrome_scope {
+            ( $name:expr ) => {
+                let _sl_cs = $crate::allocators::chrome::scope($name);
+            };
+        }
+
+        #[macro_export]
+        macro_rules! no_alloc {
+            ( $body:block ) => {
+                {
+                    let _sl_na = $crate::allocators::NoAllocGuard::enter();
+                    $body
+                }
+            };
+        }
+    }
+}
+
+
+cfg_alloc_any! {
+    #[cfg(not(feature = "std"))]
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __sl_chrome_scope {
+        ( $name:expr ) => {};
+    }
+}
+
+
 cfg_alloc_count! {
     #[macro_export]
     macro_rules! enable_global_counting_alloc {
         () => {
             #[global_allocator]
             static GLOBAL: sl_core::allocators::Counting = sl_core::allocators::Counting::default();
-        }
+        };
+
+        // Explicit inner allocator (required without `std`)
+        ( $inner:ty = $init:expr ) => {
+            #[global_allocator]
+            static GLOBAL: sl_core::allocators::Counting<$inner> = sl_core::allocators::Counting::new($init);
+        };
     }
 
     #[macro_export]
@@ -27,6 +79,7 @@ cfg_alloc_count! {
             let sl_sa = GLOBAL.counts();
 
             {
+                $crate::__sl_chrome_scope!($tag);
                 $($t)*
             }
 
@@ -36,10 +89,10 @@ cfg_alloc_count! {
                 $tag,
                 sl_sa.0,
                 sl_ea.0,
-                sl_ea.0 - sl_sa.0,
+                sl_ea.0.saturating_sub(sl_sa.0),
                 sl_sa.1,
                 sl_ea.1,
-                sl_ea.1 - sl_sa.1,
+                sl_ea.1 as isize - sl_sa.1 as isize,
             );
         }
     }
@@ -49,17 +102,20 @@ cfg_alloc_count! {
         ( $f:expr ) => {
             {
                 let sl_sa = GLOBAL.counts();
-                let ret = $f();
+                let ret = {
+                    $crate::__sl_chrome_scope!(stringify!($f));
+                    $f()
+                };
                 let sl_ea = GLOBAL.counts();
                 log::info!(
                     "<{}> Allocations (Total {} => {} ({}), Active {} => {} ({}))",
                     stringify!($f),
                     sl_sa.0,
                     sl_ea.0,
-                    sl_ea.0 - sl_sa.0,
+                    sl_ea.0.saturating_sub(sl_sa.0),
                     sl_sa.1,
                     sl_ea.1,
-                    sl_ea.1 - sl_sa.1,
+                    sl_ea.1 as isize - sl_sa.1 as isize,
                 );
                 ret
             }
@@ -68,17 +124,20 @@ cfg_alloc_count! {
         ( $f:expr, $($params:tt)*? ) => {
             {
                 let sl_sa = GLOBAL.counts();
-                let ret = $f( $($params)* );
+                let ret = {
+                    $crate::__sl_chrome_scope!(stringify!($f));
+                    $f( $($params)* )
+                };
                 let sl_ea = GLOBAL.counts();
                 log::info!(
                     "<{}> Allocations (Total {} => {} ({}), Active {} => {} ({}))",
                     stringify!($f),
                     sl_sa.0,
                     sl_ea.0,
-                    sl_ea.0 - sl_sa.0,
+                    sl_ea.0.saturating_sub(sl_sa.0),
                     sl_sa.1,
                     sl_ea.1,
-                    sl_ea.1 - sl_sa.1,
+                    sl_ea.1 as isize - sl_sa.1 as isize,
                 );
                 ret
             }
@@ -100,12 +159,14 @@ cfg_alloc_trace! {
     macro_rules! trace_block {
         ( $tag:literal; $($t:tt)* ) => {
             {
+                let _sl_cs = $crate::allocators::chrome::scope($tag);
                 $($t)*
             }
 
             let fname = std::fmt::format(format_args!(".{}_{}.log", $tag, std::process::id()));
             let mut mem_log = std::fs::File::create(fname).expect("failed to create mem log file");
             GLOBAL.dump_info(&mut mem_log);
+            sl_core::allocators::dump_regions(&mut mem_log).expect("failed to write region data");
         }
     }
 }
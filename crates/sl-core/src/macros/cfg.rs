@@ -10,6 +10,17 @@
  *
  */
 
+// Only used inside the allocator feature blocks
+#[allow(unused_macros)]
+macro_rules! cfg_std {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "std")]
+            $item
+        )*
+    }
+}
+
 macro_rules! cfg_alloc_count {
     ($($item:item)*) => {
         $(
@@ -27,3 +38,57 @@ macro_rules! cfg_alloc_trace {
         )*
     }
 }
+
+macro_rules! cfg_alloc_dump {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "alloc-dump")]
+            $item
+        )*
+    }
+}
+
+macro_rules! cfg_alloc_pages {
+    ($($item:item)*) => {
+        $(
+            #[cfg(all(feature = "alloc-pages", target_os = "linux"))]
+            $item
+        )*
+    }
+}
+
+macro_rules! cfg_alloc_shm {
+    ($($item:item)*) => {
+        $(
+            #[cfg(all(feature = "alloc-shm", target_os = "linux"))]
+            $item
+        )*
+    }
+}
+
+macro_rules! cfg_alloc_spans {
+    ($($item:item)*) => {
+        $(
+            #[cfg(all(feature = "alloc-spans", any(feature = "alloc-count", feature = "alloc-trace")))]
+            $item
+        )*
+    }
+}
+
+macro_rules! cfg_alloc_api {
+    ($($item:item)*) => {
+        $(
+            #[cfg(all(feature = "alloc-api", any(feature = "alloc-count", feature = "alloc-trace")))]
+            $item
+        )*
+    }
+}
+
+macro_rules! cfg_alloc_any {
+    ($($item:item)*) => {
+        $(
+            #[cfg(any(feature = "alloc-count", feature = "alloc-trace"))]
+            $item
+        )*
+    }
+}
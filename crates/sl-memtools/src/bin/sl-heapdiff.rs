@@ -0,0 +1,82 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_memtools
+ * Binary:  sl-heapdiff
+ *
+ * Purpose:
+ *    Diffs two heap snapshots written by `Snapshot::write` (taken from the
+ *    same run) and lists the allocations that are new in the later one,
+ *    grouped by call site.
+ *
+ */
+
+use std::{
+    fs::File,
+    io::{
+        BufReader,
+        BufWriter,
+        Write,
+    },
+};
+
+use sl_core::allocators::dump::Snapshot;
+
+
+const USAGE: &str = "usage: sl-heapdiff <before> <after> [-o <output>]";
+
+
+fn main() {
+    let mut inputs = Vec::new();
+    let mut output = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            | "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            | "-h" | "--help" => usage(),
+            | _ => inputs.push(arg),
+        }
+    }
+
+    if inputs.len() != 2 {
+        usage();
+    }
+
+    let before = read_snapshot(&inputs[0]);
+    let after = read_snapshot(&inputs[1]);
+    let diff = before.diff(&after);
+
+    let res = match output {
+        | Some(path) => {
+            let file = File::create(&path)
+                .unwrap_or_else(|e| fail(&format!("failed to create '{}': {}", path, e)));
+            let mut out = BufWriter::new(file);
+            diff.write(&mut out).and_then(|_| out.flush())
+        },
+        | None => diff.write(&mut std::io::stdout().lock()),
+    };
+
+    if let Err(e) = res {
+        fail(&format!("failed to write diff: {}", e));
+    }
+}
+
+fn read_snapshot(path: &str) -> Snapshot {
+    File::open(path)
+        .map(BufReader::new)
+        .and_then(Snapshot::read)
+        .unwrap_or_else(|e| fail(&format!("failed to read '{}': {}", path, e)))
+}
+
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1);
+}
@@ -0,0 +1,201 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_memtools
+ * Binary:  sl-memtop
+ *
+ * Purpose:
+ *    Top-like view of a running process's allocator stats. Attaches by
+ *    PID to the shared memory segment published with
+ *    `sl_core::allocators::shm::publish` and shows the allocation rate,
+ *    live bytes and peak, refreshed until the process exits. When stdout
+ *    isn't a terminal it prints one line per refresh instead.
+ *
+ */
+
+use std::{
+    io::{
+        IsTerminal,
+        Write,
+    },
+    time::Duration,
+};
+
+use sl_core::allocators::shm::{
+    Stats,
+    StatsView,
+};
+
+
+const USAGE: &str = "usage: sl-memtop <pid> [-i <interval ms>] [-n <refreshes>]";
+
+
+fn main() {
+    let mut pid = None;
+    let mut interval = 1000;
+    let mut count = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            | "-i" => interval = number(args.next()),
+            | "-n" => count = Some(number(args.next())),
+            | "-h" | "--help" => usage(),
+            | _ if pid.is_none() => pid = Some(arg.parse().unwrap_or_else(|_| usage())),
+            | _ => usage(),
+        }
+    }
+
+    let pid: u32 = pid.unwrap_or_else(|| usage());
+    let view = StatsView::attach(pid)
+        .unwrap_or_else(|e| fail(&format!("failed to attach to process {}: {}", pid, e)));
+
+    let mut screen = Screen::new(std::io::stdout().is_terminal());
+    let mut last = view.read().unwrap_or_default();
+    let mut rates = (0.0, 0.0);
+    let mut refreshes = 0;
+
+    loop {
+        std::thread::sleep(Duration::from_millis(interval));
+
+        // A segment stuck mid-update keeps showing the last good sample.
+        let (stats, stale) = match view.read() {
+            | Ok(stats) => (stats, false),
+            | Err(_) => (last, true),
+        };
+        let elapsed = stats.updated_ms.saturating_sub(last.updated_ms);
+        if elapsed > 0 {
+            let per_sec =
+                |now: u64, then: u64| now.saturating_sub(then) as f64 * 1000.0 / elapsed as f64;
+            rates = (
+                per_sec(stats.allocs, last.allocs),
+                per_sec(stats.frees, last.frees),
+            );
+            last = stats;
+        }
+
+        let alive = std::path::Path::new(&format!("/proc/{}", pid)).exists();
+        if let Err(e) = screen.draw(&view, &stats, rates, alive, stale) {
+            fail(&format!("failed to write stats: {}", e));
+        }
+
+        refreshes += 1;
+        if !alive || count.is_some_and(|n| refreshes >= n) {
+            break;
+        }
+    }
+}
+
+
+//
+// Output
+//
+struct Screen {
+    interactive: bool,
+    header:      bool,
+}
+
+impl Screen {
+    fn new(interactive: bool) -> Self {
+        Self {
+            interactive,
+            header: false,
+        }
+    }
+
+    fn draw(
+        &mut self,
+        view: &StatsView,
+        stats: &Stats,
+        rates: (f64, f64),
+        alive: bool,
+        stale: bool,
+    ) -> std::io::Result<()> {
+        let mut out = std::io::stdout().lock();
+
+        match self.interactive {
+            | true => {
+                // Home the cursor and clear the screen.
+                write!(out, "\x1b[H\x1b[2J")?;
+                writeln!(
+                    out,
+                    "sl-memtop - pid {}{}{} (published every {} ms)\n",
+                    view.pid(),
+                    if alive { "" } else { " [exited]" },
+                    if stale { " [stale]" } else { "" },
+                    view.interval_ms()
+                )?;
+                writeln!(out, "  allocs/s   {:>14.0}", rates.0)?;
+                writeln!(out, "  frees/s    {:>14.0}", rates.1)?;
+                writeln!(out, "  live       {:>14}", human(stats.live_bytes))?;
+                writeln!(out, "  peak       {:>14}", human(stats.peak_bytes))?;
+                writeln!(out, "  blocks     {:>14}", stats.live_blocks())?;
+                writeln!(out, "  allocs     {:>14}", stats.allocs)?;
+                writeln!(out, "  frees      {:>14}", stats.frees)?;
+            },
+            | false => {
+                if !self.header {
+                    writeln!(out, "allocs/s\tfrees/s\tlive\tpeak\tblocks\tallocs\tfrees")?;
+                    self.header = true;
+                }
+                writeln!(
+                    out,
+                    "{:.0}\t{:.0}\t{}\t{}\t{}\t{}\t{}",
+                    rates.0,
+                    rates.1,
+                    stats.live_bytes,
+                    stats.peak_bytes,
+                    stats.live_blocks(),
+                    stats.allocs,
+                    stats.frees
+                )?;
+                if stale {
+                    writeln!(
+                        out,
+                        "# stats segment is stuck mid-update, last sample repeated"
+                    )?;
+                }
+                if !alive {
+                    writeln!(out, "# process {} exited", view.pid())?;
+                }
+            },
+        }
+
+        out.flush()
+    }
+}
+
+fn human(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+
+fn number(arg: Option<String>) -> u64 {
+    arg.and_then(|n| n.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or_else(|| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1);
+}
@@ -0,0 +1,126 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_memtools
+ * Binary:  sl-replay
+ *
+ * Purpose:
+ *    Replays an allocation trace captured with `Recorder` against every
+ *    allocator compiled into this binary and reports the timings. Extra
+ *    allocators are enabled with cargo features (e.g. `jemalloc`); anything
+ *    else can be driven through `sl_core::allocators::replay` directly.
+ *
+ */
+
+use std::alloc::{
+    GlobalAlloc,
+    System,
+};
+
+use sl_core::allocators::replay::{
+    self,
+    AllocTrace,
+    ReplayStats,
+};
+
+
+const USAGE: &str = "usage: sl-replay <trace> [--threaded] [--iterations <n>]";
+
+
+fn main() {
+    let mut input = None;
+    let mut threaded = false;
+    let mut iterations = 5;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            | "--threaded" => threaded = true,
+            | "--iterations" => {
+                iterations = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .unwrap_or_else(|| usage())
+            },
+            | "-h" | "--help" => usage(),
+            | _ if input.is_none() => input = Some(arg),
+            | _ => usage(),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| usage());
+    let trace = std::fs::read(&input)
+        .and_then(|data| AllocTrace::read(&data))
+        .unwrap_or_else(|e| fail(&format!("failed to read '{}': {}", input, e)));
+
+    println!(
+        "Trace:\t\t{} ops, {} threads, peak {} bytes",
+        trace.events.len(),
+        trace.threads(),
+        trace.peak_bytes(),
+    );
+
+    run("System:\t\t", &trace, &System, threaded, iterations);
+
+    #[cfg(feature = "jemalloc")]
+    run(
+        "jemalloc:\t",
+        &trace,
+        &tikv_jemallocator::Jemalloc,
+        threaded,
+        iterations,
+    );
+
+    #[cfg(all(feature = "pages", target_os = "linux"))]
+    run(
+        "Pages:\t\t",
+        &trace,
+        &sl_core::allocators::PageAlloc::default(),
+        threaded,
+        iterations,
+    );
+}
+
+fn run<A: GlobalAlloc + Sync>(
+    tag: &str,
+    trace: &AllocTrace,
+    alloc: &A,
+    threaded: bool,
+    iterations: usize,
+) {
+    let mut best: Option<ReplayStats> = None;
+
+    for _ in 0..iterations {
+        let stats = match threaded {
+            | true => replay::replay_threaded(trace, alloc),
+            | false => replay::replay(trace, alloc),
+        };
+
+        if best.is_none_or(|b| stats.elapsed < b.elapsed) {
+            best = Some(stats);
+        }
+    }
+
+    if let Some(b) = best {
+        println!(
+            "{tag}{:?} (best of {}), {:.1} ns/op, {} failures",
+            b.elapsed,
+            iterations,
+            b.elapsed.as_nanos() as f64 / b.ops.max(1) as f64,
+            b.failures,
+        );
+    }
+}
+
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1);
+}
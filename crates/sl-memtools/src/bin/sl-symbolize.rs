@@ -0,0 +1,252 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_memtools
+ * Binary:  sl-symbolize
+ *
+ * Purpose:
+ *    Symbolizes a raw trace written by `DefaultTracker::raw()` against the
+ *    matching unstripped binaries and writes the same report the tracker
+ *    would have produced in-process.
+ *
+ *    Modules are matched to `--debug` binaries by build ID. Anything without
+ *    a match falls back to the path recorded in the dump (if it still exists
+ *    and has the same build ID).
+ *
+ */
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{
+        BufReader,
+        BufWriter,
+        Write,
+    },
+};
+
+use addr2line::Loader;
+use sl_core::allocators::{
+    dump::{
+        Event,
+        Module,
+        ObjectInfo,
+        RawDump,
+        Report,
+        Symbol,
+    },
+    filter::FilterPolicy,
+};
+
+
+const USAGE: &str = "usage: sl-symbolize <raw dump> [--debug <binary>]... [--keep-std] [--no-deps]
+                    [--min-size <n>] [--max-size <n>] [--max-depth <n>]
+                    [--include <path>]... [--exclude <path>]... [-o <output>]";
+
+
+fn main() {
+    let mut input = None;
+    let mut output = None;
+    let mut debug = Vec::new();
+    let mut filter = FilterPolicy::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            | "--debug" => debug.push(args.next().unwrap_or_else(|| usage())),
+            | "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            | "--keep-std" => filter.filter_std = false,
+            | "--no-deps" => filter.filter_registry = true,
+            | "--min-size" => filter.min_size = number(args.next()),
+            | "--max-size" => filter.max_size = number(args.next()),
+            | "--max-depth" => filter.max_depth = number(args.next()),
+            | "--include" => filter.include.push(args.next().unwrap_or_else(|| usage())),
+            | "--exclude" => filter.exclude.push(args.next().unwrap_or_else(|| usage())),
+            | "-h" | "--help" => usage(),
+            | _ if input.is_none() => input = Some(arg),
+            | _ => usage(),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| usage());
+    let dump = File::open(&input)
+        .map(BufReader::new)
+        .and_then(RawDump::read)
+        .unwrap_or_else(|e| fail(&format!("failed to read '{}': {}", input, e)));
+    filter.hide.extend(dump.hide.iter().cloned());
+
+    let mut symbolizer = Symbolizer::new(&dump.modules, &debug);
+
+    let res = match output {
+        | Some(path) => {
+            let file = File::create(&path)
+                .unwrap_or_else(|e| fail(&format!("failed to create '{}': {}", path, e)));
+            write_report(&mut BufWriter::new(file), &dump, &mut symbolizer, &filter)
+        },
+        | None => {
+            write_report(
+                &mut std::io::stdout().lock(),
+                &dump,
+                &mut symbolizer,
+                &filter,
+            )
+        },
+    };
+
+    if let Err(e) = res {
+        fail(&format!("failed to write report: {}", e));
+    }
+}
+
+fn write_report<Writer: Write>(
+    out: &mut Writer,
+    dump: &RawDump,
+    symbolizer: &mut Symbolizer,
+    filter: &FilterPolicy,
+) -> std::io::Result<()> {
+    let mut report = Report::new(filter);
+
+    for e in dump.events.iter() {
+        let symbols: Vec<Symbol> = match e {
+            | Event::Alloc { ips, .. } | Event::Realloc { ips, .. } => {
+                ips.iter().flat_map(|ip| symbolizer.resolve(*ip)).collect()
+            },
+            | Event::Free { .. } => Vec::new(),
+        };
+        report.record(out, e, &symbols)?;
+    }
+
+    report.finish(out)?;
+    out.flush()
+}
+
+
+//
+// Maps instruction addresses from the dump onto debug info
+//
+struct Symbolizer<'a> {
+    modules: &'a [Module],
+    debug:   HashMap<String, String>,
+    // Keyed by the module path recorded in the dump
+    loaded:  HashMap<String, Option<(ObjectInfo, Loader)>>,
+}
+
+impl<'a> Symbolizer<'a> {
+    fn new(modules: &'a [Module], debug: &[String]) -> Self {
+        let debug = debug
+            .iter()
+            .filter_map(|path| {
+                match ObjectInfo::read(path).and_then(|i| i.build_id) {
+                    | Some(id) => Some((id, path.clone())),
+                    | None => {
+                        eprintln!("warning: no build ID found in '{}', ignoring", path);
+                        None
+                    },
+                }
+            })
+            .collect();
+
+        Self {
+            modules,
+            debug,
+            loaded: HashMap::new(),
+        }
+    }
+
+    fn resolve(&mut self, ip: u64) -> Vec<Symbol> {
+        let Some(module) = self.modules.iter().find(|m| m.contains(ip))
+        else {
+            return vec![Symbol::default()];
+        };
+
+        let Some((info, loader)) = self.load(module)
+        else {
+            return vec![Symbol::default()];
+        };
+
+        // Return addresses point just past the call; back up into it.
+        let Some(probe) = info.vaddr_of(module.file_offset(ip.saturating_sub(1)))
+        else {
+            return vec![Symbol::default()];
+        };
+
+        let mut symbols = Vec::new();
+        if let Ok(mut frames) = loader.find_frames(probe) {
+            while let Ok(Some(frame)) = frames.next() {
+                symbols.push(Symbol {
+                    name:     frame
+                        .function
+                        .as_ref()
+                        .and_then(|f| f.raw_name().ok())
+                        .map(|n| rustc_demangle::demangle(&n).to_string()),
+                    filename: frame
+                        .location
+                        .as_ref()
+                        .and_then(|l| l.file)
+                        .map(|f| f.to_string()),
+                    lineno:   frame.location.as_ref().and_then(|l| l.line),
+                });
+            }
+        }
+
+        if symbols.is_empty() {
+            symbols.push(Symbol {
+                name: loader
+                    .find_symbol(probe)
+                    .map(|n| rustc_demangle::demangle(n).to_string()),
+                ..Default::default()
+            });
+        }
+
+        symbols
+    }
+
+    fn load(&mut self, module: &Module) -> Option<&(ObjectInfo, Loader)> {
+        if !self.loaded.contains_key(&module.path) {
+            let loaded = self.debug_path(module).and_then(|path| {
+                let info = ObjectInfo::read(&path)?;
+                match Loader::new(&path) {
+                    | Ok(loader) => Some((info, loader)),
+                    | Err(e) => {
+                        eprintln!("warning: failed to load debug info from '{}': {}", path, e);
+                        None
+                    },
+                }
+            });
+
+            self.loaded.insert(module.path.clone(), loaded);
+        }
+
+        self.loaded.get(&module.path)?.as_ref()
+    }
+
+    fn debug_path(&self, module: &Module) -> Option<String> {
+        if let Some(path) = module.build_id.as_ref().and_then(|id| self.debug.get(id)) {
+            return Some(path.clone());
+        }
+
+        // Fall back to the original module if it is still around and
+        // hasn't been rebuilt since the dump was taken.
+        let on_disk = ObjectInfo::read(&module.path)?;
+        match (&module.build_id, &on_disk.build_id) {
+            | (Some(a), Some(b)) if a != b => None,
+            | _ => Some(module.path.clone()),
+        }
+    }
+}
+
+
+fn number(arg: Option<String>) -> usize {
+    arg.and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1);
+}
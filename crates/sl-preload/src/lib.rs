@@ -0,0 +1,349 @@
+/*++
+ * Copyright (c) 2023-present Robert Anderson.
+ * SPDX-License-Identifier: MIT
+ *
+ * Crate:   sl_preload
+ *
+ * Purpose:
+ *    Malloc accounting for any (glibc based) process, Rust or not:
+ *
+ *      LD_PRELOAD=libsl_preload.so <command>
+ *
+ *    Interposes the malloc family and forwards to glibc's own entry
+ *    points (`__libc_malloc` and friends, so no `dlsym` bootstrapping),
+ *    keeping `Counting`-style totals. Block sizes come from
+ *    `malloc_usable_size` so allocations and frees balance no matter how
+ *    the block was obtained. Blocks the shim allocates for itself aren't
+ *    counted but are still freed through it, so live totals are clamped
+ *    at zero rather than wrapping. A summary is written to stderr at exit.
+ *
+ *    Environment:
+ *      SL_PRELOAD_STACKS=1    capture a stack for every allocation and
+ *                             write a raw dump at exit (symbolize it with
+ *                             `sl-symbolize`)
+ *      SL_PRELOAD_OUT=<path>  raw dump path (default sl-preload.<pid>.raw)
+ *
+ */
+
+// The exported functions have exactly the contracts of the libc functions
+// they replace.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    cell::Cell,
+    ffi::c_void,
+    fs::File,
+    io::{
+        BufWriter,
+        Write,
+    },
+    sync::{
+        Mutex,
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+    },
+};
+
+use sl_core::allocators::{
+    Counter,
+    ShardedCounter,
+    dump::{
+        self,
+        Event,
+    },
+};
+
+
+const STACK_DEPTH: usize = 32;
+const MALLOC_ALIGN: usize = 2 * std::mem::size_of::<usize>();
+
+static ALLOCS: ShardedCounter = ShardedCounter::ZERO;
+static FREES: ShardedCounter = ShardedCounter::ZERO;
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+static STACKS: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static EVENTS: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+
+thread_local! {
+    // Set while the shim itself is running; anything allocated in the
+    // meantime (capturing stacks, recording events) goes straight to libc.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+
+extern "C" {
+    fn __libc_malloc(size: usize) -> *mut c_void;
+    fn __libc_calloc(count: usize, size: usize) -> *mut c_void;
+    fn __libc_realloc(ptr: *mut c_void, size: usize) -> *mut c_void;
+    fn __libc_free(ptr: *mut c_void);
+    fn __libc_memalign(align: usize, size: usize) -> *mut c_void;
+}
+
+
+//
+// Interposed entry points
+//
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    let ptr = __libc_malloc(size);
+    tracked(|| on_alloc(ptr, MALLOC_ALIGN, false));
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
+    let ptr = __libc_calloc(count, size);
+    tracked(|| on_alloc(ptr, MALLOC_ALIGN, true));
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+
+    let old_size = libc::malloc_usable_size(ptr);
+    let new_ptr = __libc_realloc(ptr, size);
+
+    // realloc(ptr, 0) frees the block; on any other failure the original
+    // block is left untouched.
+    if new_ptr.is_null() && size != 0 {
+        return new_ptr;
+    }
+
+    tracked(|| {
+        match new_ptr.is_null() {
+            | true => on_free(ptr, old_size),
+            | false => on_realloc(ptr, old_size, new_ptr),
+        }
+    });
+    new_ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn reallocarray(ptr: *mut c_void, count: usize, size: usize) -> *mut c_void {
+    match count.checked_mul(size) {
+        | Some(total) => realloc(ptr, total),
+        | None => {
+            *libc::__errno_location() = libc::ENOMEM;
+            std::ptr::null_mut()
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let size = libc::malloc_usable_size(ptr);
+    __libc_free(ptr);
+    tracked(|| on_free(ptr, size));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(out: *mut *mut c_void, align: usize, size: usize) -> i32 {
+    if !align.is_power_of_two() || !align.is_multiple_of(std::mem::size_of::<usize>()) {
+        return libc::EINVAL;
+    }
+
+    let ptr = __libc_memalign(align, size);
+    if ptr.is_null() {
+        return libc::ENOMEM;
+    }
+
+    tracked(|| on_alloc(ptr, align, false));
+    *out = ptr;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: usize, size: usize) -> *mut c_void {
+    memalign(align, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: usize, size: usize) -> *mut c_void {
+    let ptr = __libc_memalign(align, size);
+    tracked(|| on_alloc(ptr, align, false));
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: usize) -> *mut c_void { memalign(page_size(), size) }
+
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: usize) -> *mut c_void {
+    let page = page_size();
+    memalign(page, size.max(1).next_multiple_of(page))
+}
+
+
+//
+// Accounting
+//
+fn on_alloc(ptr: *mut c_void, align: usize, zeroed: bool) {
+    if ptr.is_null() {
+        return;
+    }
+
+    // SAFETY: `ptr` was just handed out by glibc.
+    let size = unsafe { libc::malloc_usable_size(ptr) };
+    ALLOCS.add(1);
+    add_live(size);
+
+    if STACKS.load(Ordering::Relaxed) {
+        record(Event::Alloc {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            ptr: ptr as usize,
+            size,
+            align,
+            zeroed,
+            region: "c".to_string(),
+            ips: capture(),
+        });
+    }
+}
+
+fn on_realloc(old_ptr: *mut c_void, old_size: usize, new_ptr: *mut c_void) {
+    // SAFETY: `new_ptr` was just handed out by glibc.
+    let new_size = unsafe { libc::malloc_usable_size(new_ptr) };
+    ALLOCS.add(1);
+    FREES.add(1);
+    add_live(new_size);
+    sub_live(old_size);
+
+    if STACKS.load(Ordering::Relaxed) {
+        record(Event::Realloc {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            old_ptr: old_ptr as usize,
+            new_ptr: new_ptr as usize,
+            old_size,
+            new_size,
+            align: MALLOC_ALIGN,
+            region: "c".to_string(),
+            ips: capture(),
+        });
+    }
+}
+
+fn on_free(ptr: *mut c_void, size: usize) {
+    FREES.add(1);
+    sub_live(size);
+
+    if STACKS.load(Ordering::Relaxed) {
+        record(Event::Free { ptr: ptr as usize });
+    }
+}
+
+fn add_live(size: usize) {
+    let live = LIVE_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+}
+
+// The block may be one allocated while `BUSY`, which was never added.
+fn sub_live(size: usize) {
+    let _ = LIVE_BYTES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+        Some(live.saturating_sub(size))
+    });
+}
+
+fn capture() -> Vec<u64> {
+    let mut ips = Vec::with_capacity(STACK_DEPTH);
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as u64);
+        ips.len() < STACK_DEPTH
+    });
+    ips
+}
+
+fn record(event: Event) {
+    if let Ok(mut events) = EVENTS.lock() {
+        events.push(event);
+    }
+}
+
+/// Runs `f` unless the shim is already active on this thread (or the
+/// thread is being torn down).
+#[inline]
+fn tracked<F: FnOnce()>(f: F) {
+    let entered = BUSY.try_with(|busy| !busy.replace(true)).unwrap_or(false);
+
+    if entered {
+        f();
+        BUSY.with(|busy| busy.set(false));
+    }
+}
+
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize
+}
+
+
+//
+// Process start / exit
+//
+#[used]
+#[link_section = ".init_array"]
+static INIT: extern "C" fn() = init;
+
+extern "C" fn init() {
+    tracked(|| {
+        STACKS.store(
+            std::env::var_os("SL_PRELOAD_STACKS").is_some_and(|v| v != "0"),
+            Ordering::Relaxed,
+        );
+    });
+
+    // SAFETY: registering a plain `extern "C"` function.
+    unsafe { libc::atexit(report) };
+}
+
+extern "C" fn report() {
+    tracked(|| {
+        let stacks = STACKS.swap(false, Ordering::Relaxed);
+
+        eprintln!(
+            "sl-preload[{}]: {} allocations, {} frees, {} live, live bytes {}, peak bytes {}",
+            std::process::id(),
+            ALLOCS.get(),
+            FREES.get(),
+            ALLOCS.get().saturating_sub(FREES.get()),
+            LIVE_BYTES.load(Ordering::Relaxed),
+            PEAK_BYTES.load(Ordering::Relaxed),
+        );
+
+        if stacks {
+            let path = std::env::var("SL_PRELOAD_OUT")
+                .unwrap_or_else(|_| format!("sl-preload.{}.raw", std::process::id()));
+            if let Err(e) = write_dump(&path) {
+                eprintln!("sl-preload: failed to write '{}': {}", path, e);
+            }
+        }
+    });
+}
+
+fn write_dump(path: &str) -> std::io::Result<()> {
+    let events = std::mem::take(&mut *EVENTS.lock().expect("unable to lock events"));
+    let mut out = BufWriter::new(File::create(path)?);
+
+    dump::write_raw_header(&mut out)?;
+    // The shim's own frames top every stack.
+    dump::write_raw_hide(&mut out, "sl_preload::")?;
+    for module in dump::loaded_modules().iter() {
+        dump::write_raw_module(&mut out, module)?;
+    }
+    for event in events.iter() {
+        event.write_raw(&mut out)?;
+    }
+
+    out.flush()
+}
@@ -6,12 +6,28 @@
  *
  */
 
+use std::alloc::{
+    GlobalAlloc,
+    Layout,
+};
+
+use sl_core::allocators::{
+    AtomicCounter,
+    Counter,
+    Counting,
+    ShardedCounter,
+};
+
 // sl_core::enable_global_tracing_alloc!();
 // sl_core::enable_global_counting_alloc!();
 
 fn main() {
     env_logger::init();
 
+    // Report (rather than panic on) allocations inside the hot loops when
+    // one of the global allocators above is enabled.
+    sl_core::allocators::set_no_alloc_action(sl_core::allocators::NoAllocAction::Log);
+
     let nums = make_nums();
 
     bench_baseline(&nums);
@@ -27,17 +43,30 @@ fn main() {
         format!("Multi Sync (32768, 4):\t").as_str(),
         &nums,
     );
+
+    for threads in [1, 2, 4, 8] {
+        bench_counting::<AtomicCounter>(
+            format!("Counting Atomic ({threads}):\t").as_str(),
+            threads,
+        );
+        bench_counting::<ShardedCounter>(
+            format!("Counting Sharded ({threads}):\t").as_str(),
+            threads,
+        );
+    }
 }
 
 fn bench_baseline(nums: &[u64; 2048]) {
     let mut res = 0;
 
     let now = std::time::Instant::now();
-    for _ in 0..100_001 {
-        for n in nums {
-            res = res ^ *n;
+    sl_core::no_alloc!({
+        for _ in 0..100_001 {
+            for n in nums {
+                res = res ^ *n;
+            }
         }
-    }
+    });
 
     let elapsed = now.elapsed();
     println!("Baseline:\t\t{res} in {:?}", elapsed);
@@ -47,11 +76,13 @@ fn bench_xorer<T: Xorer + Default>(tag: &str, nums: &[u64; 2048]) {
     let mut xorer = T::default();
 
     let now = std::time::Instant::now();
-    for _ in 0..100_001 {
-        for n in nums {
-            xorer.xor(*n);
+    sl_core::no_alloc!({
+        for _ in 0..100_001 {
+            for n in nums {
+                xorer.xor(*n);
+            }
         }
-    }
+    });
 
     let elapsed = now.elapsed();
     println!("{tag}{} in {:?}", xorer.finalize(), elapsed);
@@ -204,3 +235,31 @@ impl<T: Xorer + Default, const N: usize> Xorer for MultiXor<T, N> {
         res
     }
 }
+
+
+//
+// Counting allocator scaling (shared atomic vs. sharded counters)
+//
+fn bench_counting<C: Counter + Sync>(tag: &str, threads: usize) {
+    let alloc = Counting::<std::alloc::System, C>::new(std::alloc::System);
+    let layout = Layout::from_size_align(64, 8).expect("bad layout");
+
+    let now = std::time::Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..1_000_000 {
+                    // SAFETY: the block is freed with the layout it was
+                    // allocated with.
+                    unsafe {
+                        let ptr = alloc.alloc(layout);
+                        alloc.dealloc(ptr, layout);
+                    }
+                }
+            });
+        }
+    });
+
+    let elapsed = now.elapsed();
+    println!("{tag}{} allocs in {:?}", alloc.counts().0, elapsed);
+}
@@ -0,0 +1,29 @@
+#!/usr/bin/env bash
+
+#
+# Builds sl-core for a bare metal target to check that the no_std feature
+# sets really stay off of std. Host builds with --no-default-features
+# can't catch that; std is always in the host sysroot.
+#
+#   scripts/nostd.sh [target]    (default: thumbv7em-none-eabihf)
+#
+
+set -eu
+
+SCRIPT_ROOT=`dirname $0`
+LOCAL_ROOT=$SCRIPT_ROOT/..
+
+TARGET=${1:-"thumbv7em-none-eabihf"}
+FEATURE_SETS=("" "alloc-count" "alloc-count,alloc-api")
+
+rustup target add $TARGET
+
+for FEATURES in "${FEATURE_SETS[@]}"
+do
+  echo "Building sl-core for $TARGET (features: '$FEATURES')..."
+  cargo build \
+    --manifest-path $LOCAL_ROOT/crates/sl-core/Cargo.toml \
+    --target $TARGET \
+    --no-default-features \
+    --features "$FEATURES"
+done